@@ -1,66 +1,127 @@
 use btleplug::api::bleuuid::BleUuid;
 use btleplug::api::{Central, Manager as _, Peripheral as _, ScanFilter, WriteType};
-use btleplug::api::{CentralEvent, CharPropFlags, Characteristic};
-use btleplug::platform::{Adapter, Manager, Peripheral};
-use serde_json;
+use btleplug::api::{CentralEvent, CharPropFlags, Characteristic, ValueNotification};
+use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::error::Error;
 use std::iter::Iterator;
+use std::ops::RangeInclusive;
 use std::path::Path;
-use std::time::Duration;
-use tokio::{select, time};
-use tokio_stream::StreamExt;
+use std::pin::Pin;
+use tokio::select;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::clapper::Args;
+use crate::commands::run;
 use crate::data::{
-    Data, DataType, DataView, RX_CHARACTERISTIC, RX_SERVICE, TX_CHARACTERISTIC, TX_SERVICE,
+    cell_alarms_from_bits, flags_from_bits, BtModuleInfo, ChargingState, Data, DataType, DataView,
+    CHARGE_DISCHARGE_FLAGS, DEVICE_INFO_SERVICE, RX_CHARACTERISTIC, RX_SERVICE, STATUS_1_FLAGS,
+    STATUS_2_FLAGS, TX_CHARACTERISTIC, TX_SERVICE,
 };
+use crate::modbus::{
+    CELL_VOLTS_COUNT, CELL_VOLTS_REGISTER, CHARGE_STATE_COUNT, CHARGE_STATE_REGISTER,
+    CHARGING_COUNT, CHARGING_REGISTER, CONTROLLER_ADDRESS, CONTROLLER_INFO_COUNT,
+    CONTROLLER_INFO_REGISTER, DAILY_STATS_COUNT, DAILY_STATS_REGISTER, DEFAULT_ADDRESS,
+    DEVICE_INFO_COUNT, DEVICE_INFO_REGISTER, HEALTH_COUNT, HEALTH_REGISTER, LEVELS_COUNT,
+    LEVELS_REGISTER, STATUS_COUNT, STATUS_REGISTER, TEMPS_COUNT, TEMPS_REGISTER,
+};
+use crate::poll::{save_data_to_file, timestamp};
+use crate::reassembly::{FrameBuffer, FRAGMENT_TIMEOUT};
+use crate::record::{Direction, RawCapture};
+use crate::scan::{is_renogy, DeviceFilter};
+use crate::transport::Transport;
 
-pub async fn get_bt_adapter() -> Adapter {
-    let manager = Manager::new().await.unwrap();
-    let adapters = manager.adapters().await.unwrap();
-    adapters
-        .into_iter()
-        .nth(0)
-        .expect("Bluetooth adapter to be turned on")
+/// Every bluetooth adapter on the system
+pub async fn list_adapters() -> Result<Vec<Adapter>, Box<dyn Error + Send + Sync>> {
+    let manager = Manager::new()
+        .await
+        .map_err(|e| format!("Could not reach the bluetooth stack: {e}"))?;
+    Ok(manager.adapters().await?)
 }
 
-pub async fn scan_for_devices(central: &Adapter) {
-    central
-        .start_scan(ScanFilter::default())
+/// The adapter's name and details, e.g. `hci0 (usb:v1D6Bp0246d0540)`
+pub async fn adapter_name(adapter: &Adapter) -> String {
+    adapter
+        .adapter_info()
         .await
-        .expect("Adapter closed unexpectedly");
+        .unwrap_or_else(|e| format!("unknown ({e})"))
+}
+
+/// The adapter chosen with `--adapter`, by index or name, or else the first adapter
+pub async fn get_bt_adapter(args: &Args) -> Result<Adapter, Box<dyn Error + Send + Sync>> {
+    let adapters = list_adapters().await?;
+    if adapters.is_empty() {
+        return Err("No bluetooth adapter found. Is bluetooth enabled?".into());
+    }
+    let Some(choice) = args.adapter.as_ref() else {
+        return Ok(adapters.into_iter().next().unwrap());
+    };
+
+    if let Ok(index) = choice.parse::<usize>() {
+        let count = adapters.len();
+        return adapters.into_iter().nth(index).ok_or_else(|| {
+            format!("No bluetooth adapter at index {index}, there are {count}").into()
+        });
+    }
+    for adapter in adapters {
+        let name = adapter_name(&adapter).await;
+        if name == *choice || name.split_whitespace().next() == Some(choice) {
+            return Ok(adapter);
+        }
+    }
+    Err(format!("No bluetooth adapter named `{choice}`. Run `reno adapters` to list them.").into())
+}
+
+/// Starts scanning, with a clear error when the adapter cannot
+pub async fn start_scan(
+    central: &Adapter,
+    filter: ScanFilter,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    central.start_scan(filter).await.map_err(|e| {
+        format!("Bluetooth adapter could not start scanning. Is it powered on? ({e})").into()
+    })
+}
+
+pub async fn scan_for_devices(
+    central: &Adapter,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    start_scan(central, DeviceFilter::new(args).scan_filter()).await
 }
 
-pub async fn handle_device_events(central: &Adapter, args: &Args) -> Result<(), Box<dyn Error>> {
+/// Prints every bluetooth adapter, with the index `--adapter` accepts
+pub async fn print_adapters() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let adapters = list_adapters().await?;
+    if adapters.is_empty() {
+        println!("No bluetooth adapter found. Is bluetooth enabled?");
+        return Ok(());
+    }
+    println!("Index  Adapter");
+    for (index, adapter) in adapters.iter().enumerate() {
+        println!("{index:>5}  {}", adapter_name(adapter).await);
+    }
+    Ok(())
+}
+
+pub async fn handle_device_events(
+    central: &Adapter,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut events = central.events().await?;
-    let mut connected_to_first_discovered = false;
+    let mut connected = HashSet::new();
     loop {
         select! {
             event = events.next() => {
                 match event {
                     Some(CentralEvent::DeviceDiscovered(id)) => {
                         println!("DeviceDiscovered: {:?}", id);
-                        if let Some(peripheral_id) = args.peripheral_id.as_ref() {
-                            if &id.to_string() == peripheral_id {
-                                let peripheral = central.peripheral(&id).await?;
-                                peripheral.connect().await?;
-                                read_data(&peripheral, &args).await?;
-                            }
-                        } else if let Some(peripheral_name) = args.peripheral_name.as_ref() {
-                            let peripheral = central.peripheral(&id).await?;
-                            if let Some(peripheral_properties) = peripheral.properties().await? {
-                                if &peripheral_properties.local_name.unwrap_or_default() == peripheral_name {
-                                    peripheral.connect().await?;
-                                    read_data(&peripheral, &args).await?;
-                                }
-                            }
-                        } else if !connected_to_first_discovered {
-                            println!("No device name specified. Connecting to first discovered device.");
-                            let peripheral = central.peripheral(&id).await?;
-                            peripheral.connect().await?;
-                            connected_to_first_discovered = true;
-                            read_data(&peripheral, &args).await?;
-                        }
+                        read_if_selected(central, &id, args, &mut connected).await?;
+                    }
+                    // The name, services and signal strength can arrive after discovery
+                    Some(CentralEvent::DeviceUpdated(id)) => {
+                        read_if_selected(central, &id, args, &mut connected).await?;
                     }
                     Some(CentralEvent::DeviceConnected(id)) => {
                         println!("DeviceConnected: {:?}", id);
@@ -93,105 +154,379 @@ pub async fn handle_device_events(central: &Adapter, args: &Args) -> Result<(),
     Ok(())
 }
 
-pub async fn read_data(peripheral: &Peripheral, args: &Args) -> Result<(), Box<dyn Error>> {
-    // Prioritise temps > levels > cell volts
-    // Do not get data if we have not received a response from the previous request
-
-    peripheral.discover_services().await?;
+/// Connects to and reads from the device `id`, if it is chosen and not connected yet
+async fn read_if_selected(
+    central: &Adapter,
+    id: &PeripheralId,
+    args: &Args,
+    connected: &mut HashSet<PeripheralId>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let first_renogy = args.peripheral_ids.is_empty() && args.peripheral_name.is_none();
+    if connected.contains(id) || (first_renogy && !connected.is_empty()) {
+        return Ok(());
+    }
+    let peripheral = central.peripheral(id).await?;
+    if !is_selected(&peripheral, args).await? {
+        return Ok(());
+    }
+    if first_renogy {
+        println!("No device name specified. Connecting to first matching Renogy device.");
+    }
+    peripheral.connect().await?;
+    connected.insert(id.clone());
+    read_data(&peripheral, args).await
+}
 
-    let rx_char = subscribe_to_service(peripheral).await?;
+/// Whether `peripheral` is one chosen with `--peripheral-id`, or the one with `--peripheral-name`
+///
+/// If neither is given, the first Renogy module passing the scan filters is chosen.
+async fn is_selected(
+    peripheral: &Peripheral,
+    args: &Args,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if !args.peripheral_ids.is_empty() {
+        return Ok(args.peripheral_ids.contains(&peripheral.id().to_string()));
+    }
+    if let Some(peripheral_name) = args.peripheral_name.as_ref() {
+        let local_name = peripheral.properties().await?.and_then(|p| p.local_name);
+        return Ok(local_name.as_ref() == Some(peripheral_name));
+    }
+    is_matching_renogy(peripheral, args).await
+}
 
-    let mut notification_stream = peripheral.notifications().await?;
+/// Whether `peripheral` looks like a Renogy module, and passes the scan filters
+async fn is_matching_renogy(
+    peripheral: &Peripheral,
+    args: &Args,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let Some(properties) = peripheral.properties().await? else {
+        return Ok(false);
+    };
+    Ok(is_renogy(&properties) && DeviceFilter::new(args).matches(&properties))
+}
 
-    let mut count: u64 = 0;
-    let mut request_type = RequestType::GetLevels;
-    let interval = args.inverval;
-    loop {
-        select! {
-            notification = notification_stream.next() => {
-                let notification = notification.unwrap();
-                let mut packet = notification.value;
-                let payload = DataView::new(&mut packet[3..]);
-
-                let system_time = std::time::SystemTime::now();
-                let timestamp = system_time.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
-                let data_type = handle_data(payload, &request_type);
-                let data = Data {
-                    data: data_type,
-                    timestamp,
-                };
-
-
-                if let Some(logs) = args.logs.as_ref() {
-                    if count % (interval * 5) == 0 {
-                        println!("{data:?}");
-                    }
-                    let file_path = Path::new(&logs);
-                    if let Err(e) = save_data_to_file(data, file_path).await {
-                        println!("Error saving data to file: {:?}", e);
-                    }
-                } else {
-                    println!("{data:?}");
-                }
+/// Waits until the chosen peripheral has been discovered
+pub async fn find_peripheral(
+    central: &Adapter,
+    args: &Args,
+) -> Result<Peripheral, Box<dyn Error + Send + Sync>> {
+    let mut events = central.events().await?;
 
-            },
-            _ = time::sleep(Duration::from_secs(interval)) => {
-                count += 1;
+    for peripheral in central.peripherals().await? {
+        if is_selected(&peripheral, args).await? {
+            return Ok(peripheral);
+        }
+    }
 
-                if count % (interval * 20) == 0 {
-                    request_type = RequestType::GetTemps;
-                    if let Err(e) = get_temps(&peripheral, &rx_char).await {
-                        println!("Error getting temps: {:?}", e);
-                    }
-                } else if count % (interval * 10) == 0 {
-                    request_type = RequestType::GetCellVolts;
-                    if let Err(e) = get_cell_volts(&peripheral, &rx_char).await {
-                        println!("Error getting cell volts: {:?}", e);
-                    }
-                } else {
-                    request_type = RequestType::GetLevels;
-                    if let Err(e) = get_levels(&peripheral, &rx_char).await {
-                        println!("Error getting levels: {:?}", e);
-                    }
-                }
-            },
-            //  Listen for Ctrl-C and quit the program.
-            _ = tokio::signal::ctrl_c() => {
-                println!("Ctrl-C received, quitting...");
-                break;
+    while let Some(event) = events.next().await {
+        // The name, services and signal strength can arrive after discovery
+        if let CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) = event {
+            let peripheral = central.peripheral(&id).await?;
+            if is_selected(&peripheral, args).await? {
+                return Ok(peripheral);
             }
         }
     }
-    peripheral.disconnect().await?;
-    Ok(())
+    Err("Adapter stopped sending events".into())
 }
 
-/// Pushes the data as a JSON array element to the current directory in a file called `bt-data.json`
-pub async fn save_data_to_file(data: Data, file_path: &Path) -> Result<(), Box<dyn Error>> {
-    // Create file if it does not exist, and add `[]` to it if not. Otherwise, just open.
-    if !tokio::fs::metadata(file_path).await.is_ok() {
-        tokio::fs::write(file_path, "[]").await?;
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+/// The BLE link to a connected BT module, over the RX/TX characteristic pair from
+/// `subscribe_to_service`
+pub struct BleTransport {
+    peripheral: Peripheral,
+    rx_char: Characteristic,
+    notifications: Notifications,
+    frames: FrameBuffer,
+    capture: Option<RawCapture>,
+    pub module_info: BtModuleInfo,
+}
+
+impl BleTransport {
+    /// Discovers services and subscribes to notifications on an already connected peripheral
+    pub async fn new(peripheral: Peripheral) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (rx_char, notifications) = BleTransport::subscribe(&peripheral).await?;
+        let module_info = read_module_info(&peripheral).await;
+        Ok(BleTransport {
+            peripheral,
+            rx_char,
+            notifications,
+            frames: FrameBuffer::new(FRAGMENT_TIMEOUT),
+            capture: None,
+            module_info,
+        })
     }
 
-    let file_str = tokio::fs::read_to_string("bt-data.json").await?;
-    let mut current_json: Vec<Data> = serde_json::from_str(&file_str)?;
-    current_json.push(data);
-    let new_json = serde_json::to_string(&current_json)?;
+    async fn subscribe(
+        peripheral: &Peripheral,
+    ) -> Result<(Characteristic, Notifications), Box<dyn Error + Send + Sync>> {
+        peripheral.discover_services().await?;
+        let rx_char = subscribe_to_service(peripheral).await?;
+        let notifications = peripheral.notifications().await?;
+        Ok((rx_char, notifications))
+    }
+}
 
-    tokio::fs::write("bt-data.json", new_json).await?;
+impl Transport for BleTransport {
+    /// Writes the frame to the RX characteristic
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(capture) = self.capture.as_mut() {
+            capture.record(Direction::Tx, frame);
+        }
+        self.peripheral
+            .write(&self.rx_char, frame, WriteType::WithResponse)
+            .await?;
+        Ok(())
+    }
 
-    Ok(())
+    /// Reassembles the next frame from notifications on the TX characteristic
+    async fn receive_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if let Some(frame) = self.frames.next_frame() {
+                return Some(frame);
+            }
+            let notification = self.notifications.next().await?;
+            if let Some(capture) = self.capture.as_mut() {
+                capture.record(Direction::Rx, &notification.value);
+            }
+            self.frames.push(&notification.value);
+        }
+    }
+
+    /// Connects again if the module dropped the connection, then resubscribes
+    async fn reconnect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.peripheral.is_connected().await? {
+            self.peripheral.connect().await?;
+        }
+        let (rx_char, notifications) = BleTransport::subscribe(&self.peripheral).await?;
+        self.rx_char = rx_char;
+        self.notifications = notifications;
+        self.frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.peripheral.disconnect().await?;
+        Ok(())
+    }
+
+    fn capture(&mut self, capture: RawCapture) {
+        self.capture = Some(capture);
+    }
 }
 
+/// Prints the BT module banner, and saves the module info when logging to a file
+pub async fn announce(module_info: &BtModuleInfo, args: &Args) {
+    match args.label.as_ref() {
+        Some(label) => println!("[{label}] Connected to BT module: {module_info}"),
+        None => println!("Connected to BT module: {module_info}"),
+    }
+    if let Some(logs) = args.logs.as_ref() {
+        let data = Data {
+            data: DataType::BtModule(module_info.clone()),
+            timestamp: timestamp(),
+            address: None,
+            device: args.label.clone(),
+        };
+        if let Err(e) = save_data_to_file(data, Path::new(logs)).await {
+            println!("Error saving data to file: {:?}", e);
+        }
+    }
+}
+
+/// Connects to the chosen peripheral
+pub async fn connect(
+    central: &Adapter,
+    args: &Args,
+) -> Result<BleTransport, Box<dyn Error + Send + Sync>> {
+    let peripheral = find_peripheral(central, args).await?;
+    peripheral.connect().await?;
+    let transport = BleTransport::new(peripheral).await?;
+    announce(&transport.module_info, args).await;
+    Ok(transport)
+}
+
+pub async fn read_data(
+    peripheral: &Peripheral,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let transport = BleTransport::new(peripheral.clone()).await?;
+    announce(&transport.module_info, args).await;
+    run(transport, args).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestType {
-    GetLevels,
-    GetCellVolts,
-    GetTemps,
+    Levels,
+    CellVolts,
+    Temps,
+    Health,
+    Status,
+    DeviceInfo,
+    ControllerInfo,
+    Charging,
+    DailyStats,
+    ChargeState,
+    DcCharging,
+    ChargeStage,
+}
+
+impl RequestType {
+    /// The start register and register count the request reads
+    pub fn registers(&self) -> (u16, u16) {
+        match self {
+            RequestType::Levels => (LEVELS_REGISTER, LEVELS_COUNT),
+            RequestType::CellVolts => (CELL_VOLTS_REGISTER, CELL_VOLTS_COUNT),
+            RequestType::Temps => (TEMPS_REGISTER, TEMPS_COUNT),
+            RequestType::Health => (HEALTH_REGISTER, HEALTH_COUNT),
+            RequestType::Status => (STATUS_REGISTER, STATUS_COUNT),
+            RequestType::DeviceInfo => (DEVICE_INFO_REGISTER, DEVICE_INFO_COUNT),
+            RequestType::ControllerInfo => (CONTROLLER_INFO_REGISTER, CONTROLLER_INFO_COUNT),
+            RequestType::Charging => (CHARGING_REGISTER, CHARGING_COUNT),
+            RequestType::DailyStats => (DAILY_STATS_REGISTER, DAILY_STATS_COUNT),
+            RequestType::ChargeState => (CHARGE_STATE_REGISTER, CHARGE_STATE_COUNT),
+            RequestType::DcCharging => (CHARGING_REGISTER, CHARGING_COUNT),
+            RequestType::ChargeStage => (CHARGE_STATE_REGISTER, CHARGE_STATE_COUNT),
+        }
+    }
+}
+
+/// The kind of Renogy device behind the BT module, which decides the register map to poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceProfile {
+    /// Renogy smart lithium battery
+    Battery,
+    /// Rover/Wanderer solar charge controller
+    ChargeController,
+    /// DCC series DC-DC charger with MPPT solar input
+    DcCharger,
+}
+
+impl DeviceProfile {
+    /// Modbus address the device answers on when it is the only one behind the BT module
+    pub fn default_address(&self) -> u8 {
+        match self {
+            DeviceProfile::Battery => DEFAULT_ADDRESS,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => CONTROLLER_ADDRESS,
+        }
+    }
+
+    /// Addresses that linked devices of this kind answer on
+    pub fn address_range(&self) -> RangeInclusive<u8> {
+        match self {
+            DeviceProfile::Battery => DEFAULT_ADDRESS..=DEFAULT_ADDRESS + 0x0f,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => {
+                CONTROLLER_ADDRESS..=CONTROLLER_ADDRESS
+            }
+        }
+    }
+
+    /// Every request the device understands
+    pub fn requests(&self) -> &'static [RequestType] {
+        match self {
+            DeviceProfile::Battery => &[
+                RequestType::Levels,
+                RequestType::CellVolts,
+                RequestType::Temps,
+                RequestType::Health,
+                RequestType::Status,
+                RequestType::DeviceInfo,
+            ],
+            DeviceProfile::ChargeController => &[
+                RequestType::ControllerInfo,
+                RequestType::Charging,
+                RequestType::DailyStats,
+                RequestType::ChargeState,
+            ],
+            DeviceProfile::DcCharger => &[
+                RequestType::ControllerInfo,
+                RequestType::DcCharging,
+                RequestType::DailyStats,
+                RequestType::ChargeStage,
+            ],
+        }
+    }
+
+    /// The request that identifies the device, sent once after connecting
+    pub fn identify_request(&self) -> RequestType {
+        match self {
+            DeviceProfile::Battery => RequestType::DeviceInfo,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => {
+                RequestType::ControllerInfo
+            }
+        }
+    }
+
+    /// The request to send on poll number `count`
+    pub fn scheduled_request(&self, count: u64, interval: u64) -> RequestType {
+        match self {
+            // Prioritise health > temps > cell volts > status > levels
+            DeviceProfile::Battery => {
+                if count.is_multiple_of(interval * 40) {
+                    RequestType::Health
+                } else if count.is_multiple_of(interval * 20) {
+                    RequestType::Temps
+                } else if count.is_multiple_of(interval * 10) {
+                    RequestType::CellVolts
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::Status
+                } else {
+                    RequestType::Levels
+                }
+            }
+            // Prioritise daily stats > charge state > charging
+            DeviceProfile::ChargeController => {
+                if count.is_multiple_of(interval * 10) {
+                    RequestType::DailyStats
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::ChargeState
+                } else {
+                    RequestType::Charging
+                }
+            }
+            // Prioritise daily stats > charge stage > charging
+            DeviceProfile::DcCharger => {
+                if count.is_multiple_of(interval * 10) {
+                    RequestType::DailyStats
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::ChargeStage
+                } else {
+                    RequestType::DcCharging
+                }
+            }
+        }
+    }
+
+    /// The request whose register block starts at `start`
+    pub fn request_for_register(&self, start: u16) -> Option<RequestType> {
+        self.requests()
+            .iter()
+            .find(|request| request.registers().0 == start)
+            .copied()
+    }
+}
+
+/// The count in the first register of a count-prefixed block, capped at the registers read after it
+///
+/// A device reporting more than the block holds would otherwise be read past the payload.
+fn reported_count(payload: &DataView, what: &str) -> u16 {
+    if payload.len() < 2 {
+        return 0;
+    }
+    let available = (payload.len() / 2 - 1) as u16;
+    let reported = payload.get_uint16(0);
+    if reported > available {
+        println!("Device reported {reported} {what}, but only {available} were read");
+        return available;
+    }
+    reported
 }
 
 pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
     match request_type {
-        RequestType::GetLevels => {
+        RequestType::Levels => {
             let current = f32::from(payload.get_int16(0)) / 100.0;
             let volt = f32::from(payload.get_uint16(2)) / 10.0;
             let charge_level = payload.get_uint32(4) as f32 / 1000.0;
@@ -208,16 +543,16 @@ pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
                 capacity,
             }
         }
-        RequestType::GetCellVolts => {
-            let num_cells = payload.get_uint16(0);
+        RequestType::CellVolts => {
+            let num_cells = reported_count(&payload, "cells");
             let volts = (0..num_cells)
                 .map(|i| f32::from(payload.get_uint16((1 + i) as usize * 2)) / 10.0)
                 .collect::<Vec<_>>();
             // println!("Cell voltages: {:?}", volts);
             DataType::CellVolts { cell_volts: volts }
         }
-        RequestType::GetTemps => {
-            let num_sensors = payload.get_uint16(0);
+        RequestType::Temps => {
+            let num_sensors = reported_count(&payload, "temperature sensors");
             let mut temps = Vec::new();
             for i in 0..num_sensors {
                 let temp = f32::from(payload.get_int16((1 + i) as usize * 2)) / 10.0;
@@ -226,18 +561,168 @@ pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
             // println!("Temperatures: {:?}", temps);
             DataType::Temps { temps }
         }
+        RequestType::Health => {
+            // The module total capacity is the rated capacity, not what the cells hold today
+            let design_capacity = payload.get_uint32(0) as f32 / 1000.0;
+            let cycle_count = payload.get_uint16(4);
+            let charge_volt_limit = f32::from(payload.get_uint16(6)) / 10.0;
+            let discharge_volt_limit = f32::from(payload.get_uint16(8)) / 10.0;
+            let charge_current_limit = f32::from(payload.get_uint16(10)) / 100.0;
+            let discharge_current_limit = f32::from(payload.get_int16(12)) / 100.0;
+            DataType::Health {
+                cycle_count,
+                design_capacity,
+                charge_volt_limit,
+                discharge_volt_limit,
+                charge_current_limit,
+                discharge_current_limit,
+            }
+        }
+        RequestType::Status => {
+            let mut flags = flags_from_bits(payload.get_uint16(12), &STATUS_1_FLAGS);
+            flags.extend(flags_from_bits(payload.get_uint16(14), &STATUS_2_FLAGS));
+            flags.extend(flags_from_bits(
+                payload.get_uint16(18),
+                &CHARGE_DISCHARGE_FLAGS,
+            ));
+            DataType::Status {
+                flags,
+                cell_volt_alarms: cell_alarms_from_bits(payload.get_uint32(0)),
+                cell_temp_alarms: cell_alarms_from_bits(payload.get_uint32(4)),
+            }
+        }
+        RequestType::DeviceInfo => {
+            // Registers 0x13f6-0x1415: serial number, manufacture version, mainline (firmware)
+            // version, protocol version, model, software version, manufacturer
+            DataType::DeviceInfo {
+                serial_number: payload.get_ascii(0, 16),
+                firmware_version: payload.get_ascii(18, 4),
+                model: payload.get_ascii(24, 16),
+                software_version: payload.get_ascii(40, 4),
+                manufacturer: payload.get_ascii(44, 20),
+            }
+        }
+        RequestType::ControllerInfo => {
+            // Registers 0x000c-0x0019: model, software version, hardware version, serial number
+            // Controllers have no firmware version or manufacturer registers, so the hardware
+            // version stands in for the firmware
+            DataType::DeviceInfo {
+                model: payload.get_ascii(0, 16),
+                software_version: version_string(&payload, 16),
+                firmware_version: version_string(&payload, 20),
+                serial_number: payload.get_uint32(24).to_string(),
+                manufacturer: String::new(),
+            }
+        }
+        RequestType::Charging => {
+            let temps = payload.get_uint16(6);
+            DataType::Charging {
+                battery_soc: f32::from(payload.get_uint16(0)),
+                battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+                charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+                controller_temp: sign_magnitude((temps >> 8) as u8),
+                battery_temp: sign_magnitude(temps as u8),
+                load_volt: f32::from(payload.get_uint16(8)) / 10.0,
+                load_current: f32::from(payload.get_uint16(10)) / 100.0,
+                load_power: f32::from(payload.get_uint16(12)),
+                pv_volt: f32::from(payload.get_uint16(14)) / 10.0,
+                pv_current: f32::from(payload.get_uint16(16)) / 100.0,
+                pv_power: f32::from(payload.get_uint16(18)),
+            }
+        }
+        RequestType::DcCharging => {
+            let temps = payload.get_uint16(6);
+            DataType::DcCharging {
+                battery_soc: f32::from(payload.get_uint16(0)),
+                battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+                charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+                controller_temp: sign_magnitude((temps >> 8) as u8),
+                battery_temp: sign_magnitude(temps as u8),
+                alternator_volt: f32::from(payload.get_uint16(8)) / 10.0,
+                alternator_current: f32::from(payload.get_uint16(10)) / 100.0,
+                alternator_power: f32::from(payload.get_uint16(12)),
+                pv_volt: f32::from(payload.get_uint16(14)) / 10.0,
+                pv_current: f32::from(payload.get_uint16(16)) / 100.0,
+                pv_power: f32::from(payload.get_uint16(18)),
+            }
+        }
+        RequestType::DailyStats => DataType::DailyStats {
+            min_battery_volt: f32::from(payload.get_uint16(0)) / 10.0,
+            max_battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+            max_charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+            max_discharging_current: f32::from(payload.get_uint16(6)) / 100.0,
+            max_charging_power: f32::from(payload.get_uint16(8)),
+            max_discharging_power: f32::from(payload.get_uint16(10)),
+            charging_amp_hours: f32::from(payload.get_uint16(12)),
+            discharging_amp_hours: f32::from(payload.get_uint16(14)),
+            generation: f32::from(payload.get_uint16(16)),
+            consumption: f32::from(payload.get_uint16(18)),
+        },
+        RequestType::ChargeState => {
+            let state = payload.get_uint16(0);
+            DataType::ChargeState {
+                load_on: state & 0x8000 != 0,
+                charging_state: ChargingState::from(state as u8),
+            }
+        }
+        RequestType::ChargeStage => DataType::ChargeStage {
+            charging_state: ChargingState::from(payload.get_uint16(0) as u8),
+        },
+    }
+}
+
+/// Decodes a temperature byte whose top bit is the sign
+fn sign_magnitude(byte: u8) -> f32 {
+    let magnitude = f32::from(byte & 0x7f);
+    if byte & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
     }
 }
 
+/// Formats a version register pair, `0x00`, major, minor, patch, as `V1.2.3`
+fn version_string(payload: &DataView, offset: usize) -> String {
+    let version = payload.get_uint32(offset);
+    format!(
+        "V{}.{}.{}",
+        (version >> 16) as u8,
+        (version >> 8) as u8,
+        version as u8
+    )
+}
+
+/// Reads the Device Information Service, after services have been discovered
+pub async fn read_module_info(peripheral: &Peripheral) -> BtModuleInfo {
+    let mut info = BtModuleInfo::default();
+    let Some(service) = peripheral
+        .services()
+        .into_iter()
+        .find(|s| s.uuid.to_string() == DEVICE_INFO_SERVICE)
+    else {
+        return info;
+    };
+    for characteristic in &service.characteristics {
+        if !characteristic.properties.contains(CharPropFlags::READ) {
+            continue;
+        }
+        match peripheral.read(characteristic).await {
+            Ok(value) => info.set(&characteristic.uuid.to_string(), &value),
+            Err(e) => println!("Error reading {}: {}", characteristic.uuid, e),
+        }
+    }
+    info
+}
+
 pub async fn subscribe_to_service(
     peripheral: &Peripheral,
-) -> Result<Characteristic, Box<dyn Error>> {
+) -> Result<Characteristic, Box<dyn Error + Send + Sync>> {
     let services = peripheral.services();
 
     let rx_service = services
         .into_iter()
         .find(|s| s.uuid.to_string() == RX_SERVICE)
-        .unwrap();
+        .ok_or("RX_SERVICE not found")?;
     let rx_char = rx_service
         .characteristics
         .into_iter()
@@ -245,73 +730,126 @@ pub async fn subscribe_to_service(
             c.uuid.to_string() == RX_CHARACTERISTIC
                 && c.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE)
         })
-        .expect("RX_CHARACTERISTIC not found");
+        .ok_or("RX_CHARACTERISTIC not found")?;
 
     let services = peripheral.services();
     let tx_service = services
         .into_iter()
         .find(|s| s.uuid.to_string() == TX_SERVICE)
-        .unwrap();
+        .ok_or("TX_SERVICE not found")?;
     let tx_char = tx_service
         .characteristics
         .into_iter()
         .find(|c| {
             c.uuid.to_string() == TX_CHARACTERISTIC && c.properties.contains(CharPropFlags::NOTIFY)
         })
-        .expect("TX_CHARACTERISTIC not found");
+        .ok_or("TX_CHARACTERISTIC not found")?;
 
     // Start notifications on TX_CHARACTERISTIC
     peripheral.subscribe(&tx_char).await?;
     Ok(rx_char)
 }
 
-async fn get_levels(
-    peripheral: &Peripheral,
-    rx_char: &Characteristic,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x13b2);
-    view.set_uint16(4, 0x0006);
-    view.set_uint16(6, 0x654a);
-
-    peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
-        .await?;
-    Ok(())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-async fn get_cell_volts(
-    peripheral: &Peripheral,
-    rx_char: &Characteristic,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x1388);
-    view.set_uint16(4, 0x0011);
-    view.set_uint16(6, 0x0549);
-
-    peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
-        .await?;
-    Ok(())
-}
+    #[test]
+    fn temps_are_capped_at_the_registers_read() {
+        // 9 sensors reported in the 5 register block
+        let mut payload = [0x00, 0x09, 0x00, 0xfa, 0x00, 0xfb, 0x00, 0xfc, 0xff, 0xf6];
+        match handle_data(DataView::new(&mut payload), &RequestType::Temps) {
+            DataType::Temps { temps } => assert_eq!(temps, [25.0, 25.1, 25.2, -1.0]),
+            data => panic!("expected temps, got {data:?}"),
+        }
+    }
 
-async fn get_temps(
-    peripheral: &Peripheral,
-    rx_char: &Characteristic,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x1399);
-    view.set_uint16(4, 0x0005);
-    view.set_uint16(6, 0x5543);
-
-    peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
-        .await?;
-    Ok(())
+    #[test]
+    fn cell_volts_are_capped_at_the_registers_read() {
+        let mut payload = [0u8; CELL_VOLTS_COUNT as usize * 2];
+        payload[1] = 20;
+        payload[2..].copy_from_slice(&[0x00, 0x21].repeat(16));
+        match handle_data(DataView::new(&mut payload), &RequestType::CellVolts) {
+            DataType::CellVolts { cell_volts } => assert_eq!(cell_volts, [3.3; 16]),
+            data => panic!("expected cell voltages, got {data:?}"),
+        }
+    }
+
+    #[test]
+    fn empty_count_block_decodes_to_nothing() {
+        match handle_data(DataView::new(&mut []), &RequestType::CellVolts) {
+            DataType::CellVolts { cell_volts } => assert!(cell_volts.is_empty()),
+            data => panic!("expected cell voltages, got {data:?}"),
+        }
+    }
+
+    #[test]
+    fn battery_schedule_prioritises_rarer_requests() {
+        let profile = DeviceProfile::Battery;
+        let interval = 3;
+        assert_eq!(profile.scheduled_request(0, interval), RequestType::Health);
+        assert_eq!(profile.scheduled_request(1, interval), RequestType::Levels);
+        assert_eq!(profile.scheduled_request(15, interval), RequestType::Status);
+        assert_eq!(
+            profile.scheduled_request(30, interval),
+            RequestType::CellVolts
+        );
+        assert_eq!(profile.scheduled_request(60, interval), RequestType::Temps);
+        assert_eq!(
+            profile.scheduled_request(90, interval),
+            RequestType::CellVolts
+        );
+        assert_eq!(
+            profile.scheduled_request(120, interval),
+            RequestType::Health
+        );
+    }
+
+    #[test]
+    fn controller_schedules() {
+        let interval = 1;
+        let controller = DeviceProfile::ChargeController;
+        assert_eq!(
+            controller.scheduled_request(3, interval),
+            RequestType::Charging
+        );
+        assert_eq!(
+            controller.scheduled_request(5, interval),
+            RequestType::ChargeState
+        );
+        assert_eq!(
+            controller.scheduled_request(10, interval),
+            RequestType::DailyStats
+        );
+        let charger = DeviceProfile::DcCharger;
+        assert_eq!(
+            charger.scheduled_request(3, interval),
+            RequestType::DcCharging
+        );
+        assert_eq!(
+            charger.scheduled_request(5, interval),
+            RequestType::ChargeStage
+        );
+        assert_eq!(
+            charger.scheduled_request(10, interval),
+            RequestType::DailyStats
+        );
+    }
+
+    #[test]
+    fn every_scheduled_request_can_be_decoded() {
+        for profile in [
+            DeviceProfile::Battery,
+            DeviceProfile::ChargeController,
+            DeviceProfile::DcCharger,
+        ] {
+            for count in 0..240 {
+                let request = profile.scheduled_request(count, 3);
+                assert!(profile.requests().contains(&request));
+                assert!(profile
+                    .request_for_register(request.registers().0)
+                    .is_some());
+            }
+        }
+    }
 }
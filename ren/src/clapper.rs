@@ -1,4 +1,11 @@
-use clap::Parser;
+use btleplug::api::bleuuid::uuid_from_u16;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::ble::DeviceProfile;
+use crate::settings::Setting;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -8,18 +15,195 @@ pub struct Args {
     #[arg(short, long)]
     pub logs: Option<String>,
 
+    /// Bluetooth adapter to use, by index or name, e.g. `1` or `hci1`
+    /// If not provided, the first adapter is used. List them with `reno adapters`
+    #[arg(long, global = true)]
+    pub adapter: Option<String>,
+
     /// Peripheral ID
-    /// The ID of the bluetooth device to connect to
-    #[arg(short = 'i', long)]
-    pub peripheral_id: Option<String>,
+    /// The ID of the bluetooth device to connect to. Repeat to monitor several at once
+    #[arg(short = 'i', long = "peripheral-id", global = true)]
+    pub peripheral_ids: Vec<String>,
 
     /// Peripheral name
     /// The name of the bluetooth device to connect to
-    #[arg(short = 'n', long)]
+    #[arg(short = 'n', long, global = true)]
     pub peripheral_name: Option<String>,
 
+    /// Only consider devices whose name matches this pattern, e.g. `BT-TH-*`
+    /// `*` matches any run of characters, `?` any one character
+    #[arg(long, global = true)]
+    pub name_pattern: Option<String>,
+
+    /// Only consider devices advertising this service, e.g. `fff0` or a full UUID
+    /// Repeat to accept any of several services
+    #[arg(long = "service", global = true, value_parser = parse_service)]
+    pub services: Vec<Uuid>,
+
+    /// Only consider devices with a signal at least this strong [dBm], e.g. `-80`
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    pub min_rssi: Option<i16>,
+
     /// How often to poll the bluetooth device [seconds]
     /// If not provided, defaults to 3
     #[arg(short = 't', long, default_value = "3")]
     pub inverval: u64,
+
+    /// Modbus address of a device to poll, in decimal or `0x` prefixed hex
+    /// Repeat for each device. If not provided, linked devices are discovered
+    #[arg(short, long = "address", value_parser = parse_u8)]
+    pub addresses: Vec<u8>,
+
+    /// Serial port of an RS485 adapter wired to the device, e.g. `/dev/ttyUSB0`
+    /// Used instead of bluetooth when provided
+    #[arg(long, global = true)]
+    pub serial: Option<String>,
+
+    /// Baud rate of the serial port
+    #[arg(long, default_value = "9600", global = true)]
+    pub baud: u32,
+
+    /// Scenario file to simulate a battery from, instead of connecting to one
+    #[arg(long, global = true)]
+    pub simulate: Option<PathBuf>,
+
+    /// Capture file to append every frame sent and received to, as JSON lines
+    #[arg(long, global = true)]
+    pub record_raw: Option<PathBuf>,
+
+    /// How long to wait for each response [milliseconds]
+    #[arg(long, default_value = "2000", global = true)]
+    pub timeout: u64,
+
+    /// How many times a request that timed out is sent again
+    #[arg(long, default_value = "2", global = true)]
+    pub retries: u32,
+
+    /// Consecutive missed replies after which the connection is presumed lost, and reconnected
+    #[arg(long, default_value = "3", global = true)]
+    pub max_missed: u32,
+
+    /// Config file listing the BT modules to monitor at once, as `[[devices]]` tables
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Name records are tagged with, set for each device when monitoring several
+    #[arg(skip)]
+    pub label: Option<String>,
+
+    /// Kind of device behind the bluetooth module
+    #[arg(short, long, value_enum, default_value = "battery")]
+    pub device: DeviceProfile,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Read a range of holding registers and print their raw and hex values
+    Registers {
+        /// First register to read, in decimal or `0x` prefixed hex
+        #[arg(short, long, value_parser = parse_u16)]
+        start: u16,
+
+        /// Number of registers to read
+        #[arg(short, long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..=125))]
+        count: u16,
+
+        /// Modbus address of the device, in decimal or `0x` prefixed hex
+        #[arg(short, long, default_value = "0x30", value_parser = parse_u8)]
+        address: u8,
+    },
+    /// Show the charge controller's load output state, or switch it on or off
+    Load {
+        #[arg(value_enum)]
+        switch: Option<LoadSwitch>,
+
+        /// Modbus address of the device, in decimal or `0x` prefixed hex
+        #[arg(short, long, default_value = "0xff", value_parser = parse_u8)]
+        address: u8,
+    },
+    /// Decode the frames in a `--record-raw` capture, without connecting
+    Replay { file: PathBuf },
+    /// Print every service, characteristic and descriptor of the BT module, and their values
+    Gatt {
+        /// Print the GATT table as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the bluetooth adapters, with the index `--adapter` accepts
+    Adapters,
+    /// List the bluetooth devices advertising nearby, marking likely Renogy BT modules
+    Scan {
+        /// How long to scan for, e.g. `10s`, `500ms` or `1m`
+        #[arg(long, default_value = "10s", value_parser = parse_duration)]
+        duration: Duration,
+
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Change a charge controller setting, then read it back to verify it
+    Set {
+        #[arg(value_enum)]
+        setting: Setting,
+
+        /// New value, e.g. `lithium`, `14.4` or `always-on`
+        value: String,
+
+        /// Print the frame that would be written, without connecting
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Modbus address of the device, in decimal or `0x` prefixed hex
+        #[arg(short, long, default_value = "0xff", value_parser = parse_u8)]
+        address: u8,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadSwitch {
+    On,
+    Off,
+}
+
+fn parse_service(value: &str) -> Result<Uuid, String> {
+    let short = value.strip_prefix("0x").unwrap_or(value);
+    if short.len() == 4 {
+        return u16::from_str_radix(short, 16)
+            .map(uuid_from_u16)
+            .map_err(|e| e.to_string());
+    }
+    Uuid::parse_str(value).map_err(|e| e.to_string())
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration `{value}`"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format!("unknown unit `{unit}`, expected `ms`, `s` or `m`")),
+    }
+}
+
+fn parse_u16(value: &str) -> Result<u16, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+fn parse_u8(value: &str) -> Result<u8, std::num::ParseIntError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
 }
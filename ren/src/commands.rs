@@ -0,0 +1,145 @@
+//! The subcommands that talk to a device over an open connection
+
+use std::error::Error;
+use std::time::Duration;
+
+use crate::clapper::{Args, Command, LoadSwitch};
+use crate::poll::poll_devices;
+use crate::record::RawCapture;
+use crate::settings::Setting;
+use crate::transaction::Transaction;
+use crate::transport::{Connection, Transport};
+
+/// Prints `count` registers from `start` as raw and hex values
+pub async fn dump_registers<T: Transport>(
+    connection: &mut Connection<T>,
+    address: u8,
+    start: u16,
+    count: u16,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let registers = connection.read_registers(address, start, count).await?;
+    println!("Register  Value  Hex");
+    for (register, value) in (start..).zip(registers) {
+        println!("{register:#06x}    {value:>5}  {value:#06x}");
+    }
+
+    Ok(())
+}
+
+/// Prints the frame `reno set` would write, without connecting
+pub fn print_setting_frame(
+    address: u8,
+    setting: Setting,
+    value: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let raw = setting.parse_value(value)?;
+    let frame = Transaction::write(address, setting.register(), &[raw]).frame();
+    println!(
+        "Would write {} ({raw:#06x}) to register {:#06x} at address {address:#04x}",
+        setting.format_value(raw),
+        setting.register()
+    );
+    println!("Frame: {}", hex_string(&frame));
+    Ok(())
+}
+
+/// Writes `value` to `setting`, then reads the register back to verify it
+pub async fn change_setting<T: Transport>(
+    connection: &mut Connection<T>,
+    address: u8,
+    setting: Setting,
+    value: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let raw = setting.parse_value(value)?;
+    let register = setting.register();
+
+    let current = connection.read_registers(address, register, 1).await?[0];
+    println!("Current {setting:?}: {}", setting.format_value(current));
+
+    connection
+        .write_registers(address, register, &[raw])
+        .await?;
+
+    let written = connection.read_registers(address, register, 1).await?[0];
+    if written != raw {
+        return Err(format!(
+            "{setting:?} reads back as {}, expected {}",
+            setting.format_value(written),
+            setting.format_value(raw)
+        )
+        .into());
+    }
+    println!("{setting:?} set to {}", setting.format_value(written));
+    Ok(())
+}
+
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints the load output state, after switching it when `on` is given
+pub async fn switch_load<T: Transport>(
+    connection: &mut Connection<T>,
+    address: u8,
+    on: Option<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let load_on = match on {
+        Some(on) => connection.set_load(address, on).await?,
+        None => connection.load_state(address).await?,
+    };
+
+    println!("Load output is {}", if load_on { "on" } else { "off" });
+    if on.is_some_and(|on| on != load_on) {
+        return Err("Load output did not switch. Is the load mode set to manual?".into());
+    }
+    Ok(())
+}
+
+/// Runs the subcommand over `transport`, or polls the devices without one
+///
+/// With `--record-raw`, the bytes carried are captured on the way.
+pub async fn run<T: Transport>(
+    mut transport: T,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = args.record_raw.as_ref() {
+        transport.capture(RawCapture::create(path, args.label.clone())?);
+    }
+    let timeout = Duration::from_millis(args.timeout);
+    run_command(Connection::new(transport, timeout, args.retries), args).await
+}
+
+/// Runs the subcommand, or polls the devices without one, then closes the connection
+async fn run_command<T: Transport>(
+    mut connection: Connection<T>,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let result = match args.command {
+        Some(Command::Registers {
+            start,
+            count,
+            address,
+        }) => dump_registers(&mut connection, address, start, count).await,
+        Some(Command::Load { switch, address }) => {
+            let on = switch.map(|switch| switch == LoadSwitch::On);
+            switch_load(&mut connection, address, on).await
+        }
+        Some(Command::Set {
+            setting,
+            ref value,
+            address,
+            ..
+        }) => change_setting(&mut connection, address, setting, value).await,
+        Some(Command::Replay { .. }) => Err("Replay does not need a connection".into()),
+        Some(Command::Scan { .. }) => Err("Scan does not need a connection".into()),
+        Some(Command::Adapters) => Err("Listing adapters does not need a connection".into()),
+        Some(Command::Gatt { .. }) => Err("GATT dump needs a bluetooth connection".into()),
+        None => poll_devices(&mut connection, args).await,
+    };
+    connection.close().await?;
+    result
+}
@@ -0,0 +1,210 @@
+//! Dumps the GATT table of a BT module: every service, characteristic and descriptor, with the
+//! values of those that can be read
+
+use btleplug::api::bleuuid::BleUuid;
+use btleplug::api::{CharPropFlags, Peripheral as _};
+use btleplug::platform::Peripheral;
+use serde::Serialize;
+use std::error::Error;
+use uuid::Uuid;
+
+use crate::commands::hex_string;
+
+const PROPERTY_FLAGS: [(CharPropFlags, &str); 8] = [
+    (CharPropFlags::BROADCAST, "broadcast"),
+    (CharPropFlags::READ, "read"),
+    (
+        CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        "write-without-response",
+    ),
+    (CharPropFlags::WRITE, "write"),
+    (CharPropFlags::NOTIFY, "notify"),
+    (CharPropFlags::INDICATE, "indicate"),
+    (
+        CharPropFlags::AUTHENTICATED_SIGNED_WRITES,
+        "authenticated-signed-writes",
+    ),
+    (CharPropFlags::EXTENDED_PROPERTIES, "extended-properties"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GattService {
+    pub uuid: String,
+    pub name: Option<&'static str>,
+    pub primary: bool,
+    pub characteristics: Vec<GattCharacteristic>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GattCharacteristic {
+    pub uuid: String,
+    pub name: Option<&'static str>,
+    pub properties: Vec<&'static str>,
+    pub value: Option<GattValue>,
+    pub descriptors: Vec<GattDescriptor>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GattDescriptor {
+    pub uuid: String,
+    pub name: Option<&'static str>,
+    pub value: GattValue,
+}
+
+/// A value read from a characteristic or descriptor, or why it could not be read
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GattValue {
+    Bytes {
+        hex: String,
+        /// The bytes as text, when they are all printable
+        text: Option<String>,
+    },
+    Error(String),
+}
+
+impl GattValue {
+    fn new(read: btleplug::Result<Vec<u8>>) -> Self {
+        match read {
+            Ok(bytes) => GattValue::Bytes {
+                hex: hex_string(&bytes),
+                text: printable_text(&bytes),
+            },
+            Err(e) => GattValue::Error(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for GattValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GattValue::Bytes {
+                text: Some(text), ..
+            } => write!(f, "{text:?}"),
+            GattValue::Bytes { hex, .. } => write!(f, "[{hex}]"),
+            GattValue::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+fn printable_text(bytes: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(bytes).ok()?.trim_end_matches('\0');
+    if text.is_empty() || text.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    Some(text.to_string())
+}
+
+/// The Bluetooth SIG name of a service, characteristic or descriptor the BT modules use
+pub fn gatt_name(uuid: &Uuid) -> Option<&'static str> {
+    let name = match uuid.to_short_string().as_str() {
+        "0x1800" => "Generic Access",
+        "0x180a" => "Device Information",
+        "0x2a00" => "Device Name",
+        "0x2a01" => "Appearance",
+        "0x2a04" => "Peripheral Preferred Connection Parameters",
+        "0x2a23" => "System ID",
+        "0x2a24" => "Model Number",
+        "0x2a25" => "Serial Number",
+        "0x2a26" => "Firmware Revision",
+        "0x2a27" => "Hardware Revision",
+        "0x2a28" => "Software Revision",
+        "0x2a29" => "Manufacturer Name",
+        "0x2a2a" => "IEEE 11073-20601 Regulatory Certification",
+        "0x2a50" => "PnP ID",
+        "0x2901" => "Characteristic User Description",
+        "0x2902" => "Client Characteristic Configuration",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Discovers the services of a connected peripheral, and reads every readable value
+pub async fn read_gatt(
+    peripheral: &Peripheral,
+) -> Result<Vec<GattService>, Box<dyn Error + Send + Sync>> {
+    peripheral.discover_services().await?;
+
+    let mut services = Vec::new();
+    for service in peripheral.services() {
+        let mut characteristics = Vec::new();
+        for characteristic in &service.characteristics {
+            let value = if characteristic.properties.contains(CharPropFlags::READ) {
+                Some(GattValue::new(peripheral.read(characteristic).await))
+            } else {
+                None
+            };
+            let mut descriptors = Vec::new();
+            for descriptor in &characteristic.descriptors {
+                descriptors.push(GattDescriptor {
+                    uuid: descriptor.uuid.to_string(),
+                    name: gatt_name(&descriptor.uuid),
+                    value: GattValue::new(peripheral.read_descriptor(descriptor).await),
+                });
+            }
+            characteristics.push(GattCharacteristic {
+                uuid: characteristic.uuid.to_string(),
+                name: gatt_name(&characteristic.uuid),
+                properties: PROPERTY_FLAGS
+                    .iter()
+                    .filter(|(flag, _)| characteristic.properties.contains(*flag))
+                    .map(|(_, name)| *name)
+                    .collect(),
+                value,
+                descriptors,
+            });
+        }
+        services.push(GattService {
+            uuid: service.uuid.to_string(),
+            name: gatt_name(&service.uuid),
+            primary: service.primary,
+            characteristics,
+        });
+    }
+    Ok(services)
+}
+
+/// Prints the GATT table of a connected peripheral as a tree, or as JSON
+pub async fn print_gatt(
+    peripheral: &Peripheral,
+    json: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let services = read_gatt(peripheral).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&services)?);
+        return Ok(());
+    }
+
+    for service in &services {
+        println!(
+            "Service {}{}{}",
+            service.uuid,
+            name_suffix(service.name),
+            if service.primary { "" } else { " (secondary)" }
+        );
+        for characteristic in &service.characteristics {
+            println!(
+                "  Characteristic {}{} [{}]",
+                characteristic.uuid,
+                name_suffix(characteristic.name),
+                characteristic.properties.join(", ")
+            );
+            if let Some(value) = characteristic.value.as_ref() {
+                println!("    Value: {value}");
+            }
+            for descriptor in &characteristic.descriptors {
+                println!(
+                    "    Descriptor {}{}: {}",
+                    descriptor.uuid,
+                    name_suffix(descriptor.name),
+                    descriptor.value
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn name_suffix(name: Option<&str>) -> String {
+    name.map(|name| format!(" ({name})")).unwrap_or_default()
+}
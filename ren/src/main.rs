@@ -1,20 +1,91 @@
-use ble::{get_bt_adapter, handle_device_events, scan_for_devices};
+use ble::{
+    connect, find_peripheral, get_bt_adapter, handle_device_events, print_adapters,
+    scan_for_devices,
+};
+use btleplug::api::Peripheral as _;
 use clap::Parser;
+use commands::{print_setting_frame, run};
+use gatt::print_gatt;
+use monitor::{devices, monitor_devices};
+use record::replay;
+use scan::{print_scan, DeviceFilter};
+use serial::SerialTransport;
+use simulator::{Scenario, Simulator, SimulatorTransport};
 use std::error::Error;
 
 mod ble;
 mod clapper;
+mod commands;
 mod data;
+mod gatt;
+mod modbus;
+mod monitor;
+mod poll;
+mod reassembly;
+mod record;
+mod scan;
+mod serial;
+mod settings;
+mod simulator;
+mod transaction;
+mod transport;
 
-use clapper::Args;
+use clapper::{Args, Command};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let args = Args::parse();
 
-    let central = get_bt_adapter().await;
-    scan_for_devices(&central).await;
-    handle_device_events(&central, &args).await?;
+    if let Some(Command::Set {
+        setting,
+        value,
+        dry_run: true,
+        address,
+    }) = &args.command
+    {
+        return print_setting_frame(*address, *setting, value);
+    }
+
+    if let Some(Command::Replay { file }) = &args.command {
+        return replay(file, &args).await;
+    }
+
+    if let Some(Command::Adapters) = &args.command {
+        return print_adapters().await;
+    }
+
+    if let Some(Command::Scan { duration, json }) = &args.command {
+        let filter = DeviceFilter::new(&args);
+        return print_scan(&get_bt_adapter(&args).await?, *duration, *json, &filter).await;
+    }
+
+    if let Some(path) = args.simulate.as_ref() {
+        let simulator = Simulator::new(Scenario::load(path)?);
+        return run(SimulatorTransport::new(simulator), &args).await;
+    }
+
+    if let Some(path) = args.serial.as_ref() {
+        return run(SerialTransport::open(path, args.baud)?, &args).await;
+    }
+
+    let central = get_bt_adapter(&args).await?;
+    scan_for_devices(&central, &args).await?;
+
+    if let Some(Command::Gatt { json }) = args.command {
+        let peripheral = find_peripheral(&central, &args).await?;
+        peripheral.connect().await?;
+        let printed = print_gatt(&peripheral, json).await;
+        peripheral.disconnect().await?;
+        return printed;
+    }
+
+    match args.command {
+        Some(_) => run(connect(&central, &args).await?, &args).await?,
+        None if args.config.is_some() || args.peripheral_ids.len() > 1 => {
+            monitor_devices(&central, devices(&args)?, &args).await?
+        }
+        None => handle_device_events(&central, &args).await?,
+    }
 
     Ok(())
 }
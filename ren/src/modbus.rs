@@ -0,0 +1,472 @@
+//! Modbus RTU framing for the requests sent over the Renogy BT module
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use crate::data::DataView;
+
+/// Modbus address the BT module forwards to the first (or only) battery
+pub const DEFAULT_ADDRESS: u8 = 0x30;
+
+/// Modbus address a charge controller or DC-DC charger answers on when it is alone on a BT-1/BT-2
+/// module
+pub const CONTROLLER_ADDRESS: u8 = 0xff;
+
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+/// Most registers a write multiple registers request can carry, so its byte count fits a byte
+pub const MAX_WRITE_COUNT: usize = 123;
+
+/// Set on the function code of a response when the device replies with an exception
+pub const EXCEPTION_FLAG: u8 = 0x80;
+
+// Battery registers
+
+/// Start register of the current, voltage, charge level and capacity block
+pub const LEVELS_REGISTER: u16 = 0x13b2;
+pub const LEVELS_COUNT: u16 = 0x0006;
+
+/// Start register of the design capacity, cycle count, and charge/discharge voltage and current limits
+pub const HEALTH_REGISTER: u16 = 0x13b6;
+pub const HEALTH_COUNT: u16 = 0x0007;
+
+/// Start register of the cell count followed by one register per cell voltage
+pub const CELL_VOLTS_REGISTER: u16 = 0x1388;
+pub const CELL_VOLTS_COUNT: u16 = 0x0011;
+
+/// Start register of the sensor count followed by one register per cell temperature
+pub const TEMPS_REGISTER: u16 = 0x1399;
+pub const TEMPS_COUNT: u16 = 0x0005;
+
+/// Start register of the cell alarms, status words and charge/discharge status
+pub const STATUS_REGISTER: u16 = 0x13ec;
+pub const STATUS_COUNT: u16 = 0x000a;
+
+/// Start register of the ASCII serial number, firmware and software versions, model and manufacturer
+pub const DEVICE_INFO_REGISTER: u16 = 0x13f6;
+pub const DEVICE_INFO_COUNT: u16 = 0x0020;
+
+// Charge controller (Rover/Wanderer) registers, shared by the DC-DC chargers (DCC series) except
+// where noted
+
+/// Start register of the ASCII model, software and hardware versions and serial number
+pub const CONTROLLER_INFO_REGISTER: u16 = 0x000c;
+pub const CONTROLLER_INFO_COUNT: u16 = 0x000e;
+
+/// Start register of the battery, controller temperature, load and PV readings
+///
+/// DC-DC chargers report the alternator input in place of the load.
+pub const CHARGING_REGISTER: u16 = 0x0100;
+pub const CHARGING_COUNT: u16 = 0x000a;
+
+/// Start register of today's battery voltage, current and power extremes, amp-hours and energy
+pub const DAILY_STATS_REGISTER: u16 = 0x010b;
+pub const DAILY_STATS_COUNT: u16 = 0x000a;
+
+/// Register holding the load state (high byte) and charging state (low byte)
+pub const CHARGE_STATE_REGISTER: u16 = 0x0120;
+pub const CHARGE_STATE_COUNT: u16 = 0x0001;
+
+/// Register that switches the load output on (`1`) or off (`0`) when the load is in manual mode
+pub const LOAD_SWITCH_REGISTER: u16 = 0x010a;
+
+/// Computes the Modbus CRC16 (polynomial `0xa001`, initial value `0xffff`)
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in bytes {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xa001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Builds an 8-byte request frame: address, function, start register, register count and CRC
+///
+/// The CRC is transmitted low byte first, as the Modbus RTU spec requires.
+pub fn build_request(address: u8, function: u8, start: u16, count: u16) -> [u8; 8] {
+    let mut buffer = [0u8; 8];
+    buffer[0] = address;
+    buffer[1] = function;
+    let mut view = DataView::new(&mut buffer);
+    view.set_uint16(2, start);
+    view.set_uint16(4, count);
+
+    let crc = crc16(&buffer[..6]);
+    buffer[6..].copy_from_slice(&crc.to_le_bytes());
+    buffer
+}
+
+/// Builds a read holding registers (`0x03`) request frame
+pub fn build_read_request(address: u8, start: u16, count: u16) -> [u8; 8] {
+    build_request(address, READ_HOLDING_REGISTERS, start, count)
+}
+
+/// Builds a write single register (`0x06`) request frame
+pub fn build_write_single_request(address: u8, register: u16, value: u16) -> [u8; 8] {
+    build_request(address, WRITE_SINGLE_REGISTER, register, value)
+}
+
+/// Builds a write multiple registers (`0x10`) request frame: address, function, start register,
+/// register count, byte count, the values and CRC
+///
+/// # Panics
+///
+/// If there are more than `MAX_WRITE_COUNT` values, which the byte count cannot describe.
+pub fn build_write_multiple_request(address: u8, start: u16, values: &[u16]) -> Vec<u8> {
+    assert!(
+        values.len() <= MAX_WRITE_COUNT,
+        "Cannot write {} registers in one request, the most is {MAX_WRITE_COUNT}",
+        values.len()
+    );
+    let mut buffer = vec![0u8; 7 + values.len() * 2];
+    buffer[0] = address;
+    buffer[1] = WRITE_MULTIPLE_REGISTERS;
+    buffer[6] = (values.len() * 2) as u8;
+    let mut view = DataView::new(&mut buffer);
+    view.set_uint16(2, start);
+    view.set_uint16(4, values.len() as u16);
+    for (i, value) in values.iter().enumerate() {
+        view.set_uint16(7 + i * 2, *value);
+    }
+
+    let crc = crc16(&buffer);
+    buffer.extend_from_slice(&crc.to_le_bytes());
+    buffer
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModbusError {
+    /// Fewer bytes than the header (or the byte count in it) requires
+    ShortFrame {
+        expected: usize,
+        received: usize,
+    },
+    BadCrc {
+        expected: u16,
+        received: u16,
+    },
+    /// The device answered with `function | 0x80` and an exception code
+    Exception {
+        function: u8,
+        code: u8,
+    },
+    AddressMismatch {
+        expected: u8,
+        received: u8,
+    },
+    FunctionMismatch {
+        expected: u8,
+        received: u8,
+    },
+    /// The payload length does not match the number of registers requested
+    ByteCountMismatch {
+        expected: usize,
+        received: usize,
+    },
+    /// A write response does not echo the register and value (or count) written
+    EchoMismatch {
+        expected: (u16, u16),
+        received: (u16, u16),
+    },
+    /// A response arrived with no request in flight
+    Unsolicited,
+}
+
+impl Display for ModbusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModbusError::ShortFrame { expected, received } => {
+                write!(
+                    f,
+                    "short frame: expected {expected} bytes, received {received}"
+                )
+            }
+            ModbusError::BadCrc { expected, received } => {
+                write!(
+                    f,
+                    "bad CRC: expected {expected:#06x}, received {received:#06x}"
+                )
+            }
+            ModbusError::Exception { function, code } => {
+                write!(f, "exception {code:#04x} for function {function:#04x}")
+            }
+            ModbusError::AddressMismatch { expected, received } => {
+                write!(
+                    f,
+                    "address mismatch: expected {expected:#04x}, received {received:#04x}"
+                )
+            }
+            ModbusError::FunctionMismatch { expected, received } => {
+                write!(
+                    f,
+                    "function mismatch: expected {expected:#04x}, received {received:#04x}"
+                )
+            }
+            ModbusError::ByteCountMismatch { expected, received } => {
+                write!(
+                    f,
+                    "byte count mismatch: expected {expected}, received {received}"
+                )
+            }
+            ModbusError::EchoMismatch { expected, received } => {
+                write!(
+                    f,
+                    "write echo mismatch: expected {:#06x} {:#06x}, received {:#06x} {:#06x}",
+                    expected.0, expected.1, received.0, received.1
+                )
+            }
+            ModbusError::Unsolicited => write!(f, "response received with no request in flight"),
+        }
+    }
+}
+
+impl Error for ModbusError {}
+
+/// A validated response frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub address: u8,
+    pub function: u8,
+    /// The register data, without the header or CRC
+    ///
+    /// For writes, the echoed start register and value (or register count).
+    pub payload: Vec<u8>,
+}
+
+/// The full length of the frame starting with `header`, including the CRC
+///
+/// `None` until the address, function and byte count (or exception code) are known.
+pub fn frame_len(header: &[u8]) -> Option<usize> {
+    if header.len() < 3 {
+        return None;
+    }
+    if header[1] & EXCEPTION_FLAG != 0 {
+        // address, function, exception code and CRC
+        Some(5)
+    } else if header[1] == WRITE_SINGLE_REGISTER || header[1] == WRITE_MULTIPLE_REGISTERS {
+        // address, function, start register, value or register count, and CRC
+        Some(8)
+    } else {
+        // address, function, byte count, data and CRC
+        Some(3 + usize::from(header[2]) + 2)
+    }
+}
+
+/// Validates a response frame and extracts its payload
+///
+/// Bytes after the CRC are ignored.
+pub fn parse_response(frame: &[u8], address: u8, function: u8) -> Result<Response, ModbusError> {
+    // address, function, byte count (or exception code), and CRC
+    if frame.len() < 5 {
+        return Err(ModbusError::ShortFrame {
+            expected: 5,
+            received: frame.len(),
+        });
+    }
+
+    let expected_len = frame_len(frame).unwrap_or(5);
+    if frame.len() < expected_len {
+        return Err(ModbusError::ShortFrame {
+            expected: expected_len,
+            received: frame.len(),
+        });
+    }
+
+    let crc_offset = expected_len - 2;
+    let expected_crc = crc16(&frame[..crc_offset]);
+    let received_crc = u16::from_le_bytes([frame[crc_offset], frame[crc_offset + 1]]);
+    if expected_crc != received_crc {
+        return Err(ModbusError::BadCrc {
+            expected: expected_crc,
+            received: received_crc,
+        });
+    }
+
+    if frame[0] != address {
+        return Err(ModbusError::AddressMismatch {
+            expected: address,
+            received: frame[0],
+        });
+    }
+
+    if frame[1] & EXCEPTION_FLAG != 0 {
+        return Err(ModbusError::Exception {
+            function: frame[1] & !EXCEPTION_FLAG,
+            code: frame[2],
+        });
+    }
+
+    if frame[1] != function {
+        return Err(ModbusError::FunctionMismatch {
+            expected: function,
+            received: frame[1],
+        });
+    }
+
+    let payload_offset = if function == READ_HOLDING_REGISTERS {
+        3
+    } else {
+        2
+    };
+    Ok(Response {
+        address: frame[0],
+        function: frame[1],
+        payload: frame[payload_offset..crc_offset].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_requests_match_the_baseline_frames() {
+        // The frames the app sent before the CRC was computed, with the CRC written high byte
+        // first as `0x654a`, `0x0549` and `0x5543`
+        assert_eq!(
+            build_read_request(DEFAULT_ADDRESS, LEVELS_REGISTER, LEVELS_COUNT),
+            [0x30, 0x03, 0x13, 0xb2, 0x00, 0x06, 0x65, 0x4a]
+        );
+        assert_eq!(
+            build_read_request(DEFAULT_ADDRESS, CELL_VOLTS_REGISTER, CELL_VOLTS_COUNT),
+            [0x30, 0x03, 0x13, 0x88, 0x00, 0x11, 0x05, 0x49]
+        );
+        assert_eq!(
+            build_read_request(DEFAULT_ADDRESS, TEMPS_REGISTER, TEMPS_COUNT),
+            [0x30, 0x03, 0x13, 0x99, 0x00, 0x05, 0x55, 0x43]
+        );
+    }
+
+    #[test]
+    fn crc_is_sent_low_byte_first() {
+        let frame = build_read_request(DEFAULT_ADDRESS, LEVELS_REGISTER, LEVELS_COUNT);
+        assert_eq!(crc16(&frame[..6]), 0x4a65);
+        // The CRC over a frame including its own CRC is zero
+        assert_eq!(crc16(&frame), 0);
+    }
+
+    #[test]
+    fn write_multiple_request_holds_up_to_the_limit() {
+        let values = vec![0x0102; MAX_WRITE_COUNT];
+        let frame = build_write_multiple_request(DEFAULT_ADDRESS, 0xe002, &values);
+        assert_eq!(frame[..7], [0x30, 0x10, 0xe0, 0x02, 0x00, 0x7b, 0xf6]);
+        assert_eq!(frame.len(), 7 + MAX_WRITE_COUNT * 2 + 2);
+        assert_eq!(crc16(&frame), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot write 124 registers")]
+    fn write_multiple_request_rejects_more_than_the_limit() {
+        build_write_multiple_request(DEFAULT_ADDRESS, 0xe002, &[0; MAX_WRITE_COUNT + 1]);
+    }
+
+    /// `bytes` followed by their CRC
+    fn with_crc(bytes: &[u8]) -> Vec<u8> {
+        let mut frame = bytes.to_vec();
+        frame.extend_from_slice(&crc16(bytes).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn parses_read_response_payload() {
+        let mut frame = with_crc(&[0x30, 0x03, 0x04, 0x00, 0x04, 0x00, 0xfa]);
+        // Bytes after the CRC are ignored
+        frame.push(0x00);
+        let response = parse_response(&frame, 0x30, READ_HOLDING_REGISTERS).unwrap();
+        assert_eq!(response.address, 0x30);
+        assert_eq!(response.function, READ_HOLDING_REGISTERS);
+        assert_eq!(response.payload, [0x00, 0x04, 0x00, 0xfa]);
+    }
+
+    #[test]
+    fn parses_write_echo_payload() {
+        let frame = with_crc(&[0xff, 0x06, 0x01, 0x0a, 0x00, 0x01]);
+        let response = parse_response(&frame, 0xff, WRITE_SINGLE_REGISTER).unwrap();
+        assert_eq!(response.payload, [0x01, 0x0a, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn rejects_frame_shorter_than_a_header() {
+        assert_eq!(
+            parse_response(&[0x30, 0x03, 0x02], 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::ShortFrame {
+                expected: 5,
+                received: 3
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_frame_shorter_than_its_byte_count() {
+        let frame = with_crc(&[0x30, 0x03, 0x04, 0x00, 0x04]);
+        assert_eq!(
+            parse_response(&frame, 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::ShortFrame {
+                expected: 9,
+                received: 7
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut frame = with_crc(&[0x30, 0x03, 0x02, 0x00, 0x04]);
+        frame[4] ^= 0xff;
+        assert!(matches!(
+            parse_response(&frame, 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::BadCrc { .. })
+        ));
+    }
+
+    #[test]
+    fn reports_exception() {
+        let frame = with_crc(&[0x30, 0x83, 0x02]);
+        assert_eq!(
+            parse_response(&frame, 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::Exception {
+                function: READ_HOLDING_REGISTERS,
+                code: 0x02
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_address_mismatch() {
+        let frame = with_crc(&[0x31, 0x03, 0x02, 0x00, 0x04]);
+        assert_eq!(
+            parse_response(&frame, 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::AddressMismatch {
+                expected: 0x30,
+                received: 0x31
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_function_mismatch() {
+        let frame = with_crc(&[0x30, 0x06, 0x01, 0x0a, 0x00, 0x01]);
+        assert_eq!(
+            parse_response(&frame, 0x30, READ_HOLDING_REGISTERS),
+            Err(ModbusError::FunctionMismatch {
+                expected: READ_HOLDING_REGISTERS,
+                received: WRITE_SINGLE_REGISTER
+            })
+        );
+    }
+
+    #[test]
+    fn frame_len_needs_a_header() {
+        assert_eq!(frame_len(&[0x30, 0x03]), None);
+        assert_eq!(frame_len(&[0x30, 0x83, 0x02]), Some(5));
+        assert_eq!(frame_len(&[0x30, 0x10, 0x00]), Some(8));
+        assert_eq!(frame_len(&[0x30, 0x03, 0x0c]), Some(17));
+    }
+}
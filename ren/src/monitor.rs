@@ -0,0 +1,143 @@
+//! Monitors several BT modules at once, each on its own task
+
+use btleplug::platform::Adapter;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+use tokio::task::JoinSet;
+use tokio::{select, time};
+
+use crate::ble::{connect, DeviceProfile};
+use crate::clapper::Args;
+use crate::commands::run;
+use crate::transport::{MAX_RECONNECT_DELAY, RECONNECT_DELAY};
+
+/// The config file given with `--config`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let config = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&config)?)
+    }
+}
+
+/// A BT module to monitor
+///
+/// Settings left out fall back to the command line arguments.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    pub peripheral_id: String,
+    /// Name its records are tagged with, instead of the peripheral ID
+    pub name: Option<String>,
+    /// Kind of device behind the module
+    pub device: Option<DeviceProfile>,
+    /// Modbus addresses to poll, instead of discovering them
+    #[serde(default)]
+    pub addresses: Vec<u8>,
+}
+
+impl DeviceConfig {
+    fn new(peripheral_id: &str) -> Self {
+        DeviceConfig {
+            peripheral_id: peripheral_id.to_string(),
+            name: None,
+            device: None,
+            addresses: Vec::new(),
+        }
+    }
+
+    /// The arguments narrowed to this device, for its own task
+    fn args(&self, args: &Args) -> Args {
+        let mut args = args.clone();
+        args.peripheral_ids = vec![self.peripheral_id.clone()];
+        args.peripheral_name = None;
+        args.label = Some(self.name.clone().unwrap_or(self.peripheral_id.clone()));
+        if let Some(device) = self.device {
+            args.device = device;
+        }
+        if !self.addresses.is_empty() {
+            args.addresses = self.addresses.clone();
+        }
+        args
+    }
+}
+
+/// The devices to monitor: those in the config file, then any other `--peripheral-id`
+pub fn devices(args: &Args) -> Result<Vec<DeviceConfig>, Box<dyn Error + Send + Sync>> {
+    let mut devices = match args.config.as_ref() {
+        Some(path) => Config::load(path)?.devices,
+        None => Vec::new(),
+    };
+    for peripheral_id in &args.peripheral_ids {
+        if !devices.iter().any(|d| d.peripheral_id == *peripheral_id) {
+            devices.push(DeviceConfig::new(peripheral_id));
+        }
+    }
+    if devices.is_empty() {
+        return Err(
+            "No devices to monitor. Add them to the config file or with --peripheral-id".into(),
+        );
+    }
+    Ok(devices)
+}
+
+/// Connects to and polls every device at once, until Ctrl-C
+///
+/// Records from every device are tagged with its name, and written to the same log file.
+pub async fn monitor_devices(
+    central: &Adapter,
+    devices: Vec<DeviceConfig>,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut monitors = JoinSet::new();
+    for device in devices {
+        let central = central.clone();
+        let args = device.args(args);
+        monitors.spawn(async move {
+            if let Err(e) = monitor_device(&central, &args).await {
+                println!(
+                    "[{}] Stopped monitoring: {e}",
+                    args.label.unwrap_or_default()
+                );
+            }
+        });
+    }
+    while monitors.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Connects to one device, retrying with backoff until it is found, then polls it
+///
+/// Once connected, polling reconnects by itself.
+async fn monitor_device(
+    central: &Adapter,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let label = args.label.clone().unwrap_or_default();
+    let mut delay = RECONNECT_DELAY;
+    let transport = loop {
+        let connected = select! {
+            connected = connect(central, args) => connected.map_err(|e| e.to_string()),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        let error = match connected {
+            Ok(transport) => break transport,
+            Err(error) => error,
+        };
+        println!(
+            "[{label}] Error connecting: {error}. Retrying in {}s",
+            delay.as_secs()
+        );
+        select! {
+            _ = time::sleep(delay) => {},
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    };
+    run(transport, args).await
+}
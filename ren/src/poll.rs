@@ -0,0 +1,291 @@
+//! Identifies the devices behind a connection and polls them on their profile's schedule
+
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::{select, time};
+
+use crate::ble::handle_data;
+use crate::clapper::Args;
+use crate::data::{Data, DataType, DataView};
+use crate::transaction::{Transaction, Transactions};
+use crate::transport::{Connection, Transport};
+
+/// How long the device may stay silent before the connection is presumed lost
+///
+/// At least three polling intervals, so a slow schedule is not mistaken for silence.
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Identifies each device behind the BT module, and returns the addresses that answered
+///
+/// Without `--address`, addresses are probed upwards from the profile's default address, with a
+/// single attempt each. Linked batteries answer on consecutive addresses, so probing stops at the
+/// first one that does not.
+async fn discover_addresses<T: Transport>(connection: &mut Connection<T>, args: &Args) -> Vec<u8> {
+    let profile = args.device;
+    let request_type = profile.identify_request();
+    let (start, count) = request_type.registers();
+
+    let probing = args.addresses.is_empty();
+    let candidates = if probing {
+        profile.address_range().collect()
+    } else {
+        args.addresses.clone()
+    };
+
+    let mut addresses = Vec::new();
+    for address in candidates {
+        let transaction = Transaction::read(address, start, count);
+        let identified = if probing {
+            connection.probe(&transaction).await
+        } else {
+            connection.request(&transaction).await
+        };
+        match identified {
+            Ok(response) => {
+                let mut packet = response.payload;
+                let data = Data {
+                    data: handle_data(DataView::new(&mut packet), &request_type),
+                    timestamp: timestamp(),
+                    address: Some(address),
+                    device: args.label.clone(),
+                };
+                log_data(data, args, 0).await;
+                addresses.push(address);
+            }
+            Err(e) if probing => {
+                println!("No device at address {address:#04x}: {e}");
+                break;
+            }
+            Err(e) => {
+                // Poll it anyway, it may only have missed this request
+                println!("Error identifying device at address {address:#04x}: {e}");
+                addresses.push(address);
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        println!("No device answered. Polling the default address.");
+        addresses.push(profile.default_address());
+    }
+    addresses
+}
+
+/// Identifies the devices on `connection`, then polls them on the profile's schedule until Ctrl-C
+pub async fn poll_devices<T: Transport>(
+    connection: &mut Connection<T>,
+    args: &Args,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Do not get data if we have not received a response from the previous request, unless it timed out
+
+    let profile = args.device;
+    let addresses = discover_addresses(connection, args).await;
+    println!("Polling addresses: {:02x?}", addresses);
+
+    let mut count: u64 = 0;
+    let timeout = Duration::from_millis(args.timeout);
+    let mut transactions = Transactions::new(timeout);
+    // Replies missed in a row, and times the request in flight has been sent again
+    let mut missed: u32 = 0;
+    let mut retries: u32 = 0;
+
+    let interval = args.inverval;
+    let silence_timeout = SILENCE_TIMEOUT.max(Duration::from_secs(interval * 3));
+    let mut last_frame = Instant::now();
+    loop {
+        let lost: Option<String> = select! {
+            frame = connection.next_frame() => match frame {
+                Some(frame) => {
+                    // Only a reply to the request in flight shows the link is healthy
+                    if handle_frame(&frame, &mut transactions, args, count).await {
+                        last_frame = Instant::now();
+                        missed = 0;
+                    }
+                    None
+                }
+                None => Some("Connection closed".to_string()),
+            },
+            _ = time::sleep(Duration::from_secs(interval)) => {
+                count += 1;
+
+                if last_frame.elapsed() > silence_timeout {
+                    Some(format!("No response for {}s", last_frame.elapsed().as_secs()))
+                } else if let Some(expired) = transactions.take_expired() {
+                    // Watchdog: too many missed replies in a row mean the link is unhealthy
+                    missed += 1;
+                    if missed >= args.max_missed {
+                        Some(format!("{missed} replies missed"))
+                    } else if retries < args.retries {
+                        retries += 1;
+                        println!("Retrying ({retries}/{})", args.retries);
+                        send_request(connection, &mut transactions, expired.retry()).await
+                    } else {
+                        None
+                    }
+                } else if !transactions.is_idle() {
+                    None
+                } else {
+                    // Take turns, so every device works through the whole schedule
+                    let device_count = addresses.len() as u64;
+                    let address = addresses[(count % device_count) as usize];
+                    let request_type = profile.scheduled_request(count / device_count, interval);
+                    let (start, register_count) = request_type.registers();
+                    retries = 0;
+                    let transaction = Transaction::read(address, start, register_count);
+                    send_request(connection, &mut transactions, transaction).await
+                }
+            },
+            //  Listen for Ctrl-C and quit the program.
+            _ = tokio::signal::ctrl_c() => {
+                println!("Ctrl-C received, quitting...");
+                break;
+            }
+        };
+
+        if let Some(reason) = lost {
+            if !reconnect(connection, args, &reason).await {
+                break;
+            }
+            transactions = Transactions::new(timeout);
+            last_frame = Instant::now();
+            missed = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `transaction` and keeps it in flight, or returns why the connection is lost
+async fn send_request<T: Transport>(
+    connection: &mut Connection<T>,
+    transactions: &mut Transactions,
+    transaction: Transaction,
+) -> Option<String> {
+    match connection.send(&transaction).await {
+        Ok(()) => {
+            transactions.begin(transaction);
+            None
+        }
+        Err(e) => Some(format!("Error sending request: {e}")),
+    }
+}
+
+/// Decodes a response frame against the request it answers, and logs it
+///
+/// Returns whether the frame completed the request in flight.
+async fn handle_frame(
+    frame: &[u8],
+    transactions: &mut Transactions,
+    args: &Args,
+    count: u64,
+) -> bool {
+    let (transaction, response) = match transactions.complete(frame) {
+        Ok(completed) => completed,
+        Err(e) => {
+            println!("Error parsing response: {e}");
+            return false;
+        }
+    };
+    let Some(request_type) = args.device.request_for_register(transaction.start) else {
+        println!("No decoder for register {:#06x}", transaction.start);
+        return true;
+    };
+    let mut packet = response.payload;
+    let payload = DataView::new(&mut packet);
+
+    let data_type = handle_data(payload, &request_type);
+    let data = Data {
+        data: data_type,
+        timestamp: timestamp(),
+        address: Some(transaction.address),
+        device: args.label.clone(),
+    };
+    log_data(data, args, count).await;
+    true
+}
+
+/// Logs the lost connection, and reconnects with backoff
+///
+/// Returns `false` if Ctrl-C was received before the connection came back.
+async fn reconnect<T: Transport>(
+    connection: &mut Connection<T>,
+    args: &Args,
+    reason: &str,
+) -> bool {
+    let data = Data {
+        data: DataType::Disconnected {
+            reason: reason.to_string(),
+        },
+        timestamp: timestamp(),
+        address: None,
+        device: args.label.clone(),
+    };
+    log_data(data, args, 0).await;
+
+    let attempts = select! {
+        attempts = connection.reconnect() => attempts,
+        _ = tokio::signal::ctrl_c() => {
+            println!("Ctrl-C received, quitting...");
+            return false;
+        }
+    };
+    let data = Data {
+        data: DataType::Reconnected { attempts },
+        timestamp: timestamp(),
+        address: None,
+        device: args.label.clone(),
+    };
+    log_data(data, args, 0).await;
+    true
+}
+
+pub fn timestamp() -> u128 {
+    let system_time = std::time::SystemTime::now();
+    system_time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+/// Prints `data`, and saves it when logging to a file
+///
+/// When logging to a file, only every few polls are printed.
+pub async fn log_data(data: Data, args: &Args, count: u64) {
+    if let Some(logs) = args.logs.as_ref() {
+        if count.is_multiple_of(args.inverval * 5) {
+            println!("{data:?}");
+        }
+        let file_path = Path::new(&logs);
+        if let Err(e) = save_data_to_file(data, file_path).await {
+            println!("Error saving data to file: {:?}", e);
+        }
+    } else {
+        println!("{data:?}");
+    }
+}
+
+/// Held while the log file is rewritten, so devices monitored at once cannot lose each other's data
+static LOG_FILE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Pushes the data as a JSON array element to the file at `file_path`
+pub async fn save_data_to_file(
+    data: Data,
+    file_path: &Path,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let _lock = LOG_FILE_LOCK.lock().await;
+
+    // Create file if it does not exist, and add `[]` to it if not. Otherwise, just open.
+    if tokio::fs::metadata(file_path).await.is_err() {
+        tokio::fs::write(file_path, "[]").await?;
+    }
+
+    let file_str = tokio::fs::read_to_string(file_path).await?;
+    let mut current_json: Vec<Data> = serde_json::from_str(&file_str)?;
+    current_json.push(data);
+    let new_json = serde_json::to_string(&current_json)?;
+
+    tokio::fs::write(file_path, new_json).await?;
+
+    Ok(())
+}
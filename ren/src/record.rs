@@ -0,0 +1,338 @@
+//! Raw captures of the bytes sent and received, and offline replay of them through the decoder
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ble::handle_data;
+use crate::clapper::Args;
+use crate::data::{Data, DataView};
+use crate::modbus::READ_HOLDING_REGISTERS;
+use crate::poll::{log_data, timestamp};
+use crate::reassembly::{FrameBuffer, FRAGMENT_TIMEOUT};
+use crate::transaction::{Transaction, Transactions, REQUEST_TIMEOUT};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// One line of a raw capture
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawFrame {
+    pub timestamp: u128,
+    pub direction: Direction,
+    /// A whole request frame, or the bytes of one notification or serial read
+    pub frame: Vec<u8>,
+    /// Name of the monitored BT module the bytes went to or came from, when monitoring several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// Appends the bytes a transport carries to a JSON lines capture file
+///
+/// Bytes are recorded as they are written and read, before any frames are reassembled from them,
+/// so a capture shows exactly what the link carried.
+pub struct RawCapture {
+    file: File,
+    /// Tags every line, so the devices sharing a capture can be told apart on replay
+    device: Option<String>,
+}
+
+impl RawCapture {
+    pub fn create(
+        path: &Path,
+        device: Option<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(RawCapture { file, device })
+    }
+
+    /// Writes synchronously, so bytes cannot be lost to a cancelled `select!` branch
+    ///
+    /// Each line is a single appending write, so devices monitored at once can share a capture.
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        let raw_frame = RawFrame {
+            timestamp: timestamp(),
+            direction,
+            frame: bytes.to_vec(),
+            device: self.device.clone(),
+        };
+        let written = serde_json::to_string(&raw_frame)
+            .map_err(Box::<dyn Error + Send + Sync>::from)
+            .and_then(|line| Ok(self.file.write_all(format!("{line}\n").as_bytes())?));
+        if let Err(e) = written {
+            println!("Error recording frame: {:?}", e);
+        }
+    }
+}
+
+/// The replay state of one device in a capture
+struct ReplayedLink {
+    transactions: Transactions,
+    frames: FrameBuffer,
+    /// When the request in flight was captured, so it times out on the capture's clock
+    sent_at: Option<u128>,
+}
+
+impl ReplayedLink {
+    fn new() -> Self {
+        ReplayedLink {
+            // Requests expire by the captured timestamps, however fast the replay runs
+            transactions: Transactions::new(Duration::MAX),
+            frames: FrameBuffer::new(FRAGMENT_TIMEOUT),
+            sent_at: None,
+        }
+    }
+}
+
+/// Decodes the responses in a raw capture, as if they had just been received
+///
+/// Received bytes are reassembled into frames with the gaps between them as captured, and each
+/// response is matched to the request before it, and decoded for the `--device` profile. Each
+/// device in a shared capture is reassembled and matched on its own. A reply captured more than
+/// `REQUEST_TIMEOUT` after its request is dropped, as it was live.
+pub async fn replay(path: &Path, args: &Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut links: HashMap<Option<String>, ReplayedLink> = HashMap::new();
+    // The capture's timeline, starting now
+    let started = Instant::now();
+    let mut first_timestamp = None;
+
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let raw_frame: RawFrame = serde_json::from_str(&line)?;
+        let link = links
+            .entry(raw_frame.device.clone())
+            .or_insert_with(ReplayedLink::new);
+        if raw_frame.direction == Direction::Tx {
+            match Transaction::from_request(&raw_frame.frame) {
+                Some(transaction) => {
+                    link.transactions.begin(transaction);
+                    link.sent_at = Some(raw_frame.timestamp);
+                }
+                None => println!("Unrecognised request: {:02x?}", raw_frame.frame),
+            }
+            continue;
+        }
+
+        let waited = link
+            .sent_at
+            .map(|sent_at| raw_frame.timestamp.saturating_sub(sent_at));
+        if waited.is_some_and(|waited| waited > REQUEST_TIMEOUT.as_millis()) {
+            link.transactions.expire();
+            link.sent_at = None;
+        }
+
+        let first_timestamp = *first_timestamp.get_or_insert(raw_frame.timestamp);
+        let offset = raw_frame.timestamp.saturating_sub(first_timestamp) as u64;
+        link.frames
+            .push_at(&raw_frame.frame, started + Duration::from_millis(offset));
+        while let Some(frame) = link.frames.next_frame() {
+            let device = raw_frame.device.clone().or(args.label.clone());
+            replay_response(
+                &frame,
+                &mut link.transactions,
+                raw_frame.timestamp,
+                device,
+                args,
+            )
+            .await;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a reassembled response frame against the request it answers, and logs it
+async fn replay_response(
+    frame: &[u8],
+    transactions: &mut Transactions,
+    timestamp: u128,
+    device: Option<String>,
+    args: &Args,
+) {
+    let (transaction, response) = match transactions.complete(frame) {
+        Ok(completed) => completed,
+        Err(e) => {
+            println!("Error parsing response: {e}");
+            return;
+        }
+    };
+    if transaction.function != READ_HOLDING_REGISTERS {
+        println!(
+            "[{:#04x}] Wrote register {:#06x}",
+            transaction.address, transaction.start
+        );
+        return;
+    }
+    let Some(request_type) = args.device.request_for_register(transaction.start) else {
+        println!("No decoder for register {:#06x}", transaction.start);
+        return;
+    };
+    let mut packet = response.payload;
+    let data = Data {
+        data: handle_data(DataView::new(&mut packet), &request_type),
+        timestamp,
+        address: Some(transaction.address),
+        device,
+    };
+    log_data(data, args, 0).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::data::DataType;
+    use crate::modbus::{build_read_request, crc16, DEFAULT_ADDRESS, TEMPS_COUNT, TEMPS_REGISTER};
+    use crate::simulator::{Scenario, Simulator, SimulatorTransport};
+    use crate::transport::{Connection, Transport};
+
+    /// A file in the temp directory, removed when dropped
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("reno-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Replays the capture at `capture`, and returns what was logged
+    async fn replayed(capture: &TempFile) -> Vec<Data> {
+        let logs = TempFile::new("logs.json");
+        let args = Args::parse_from(["reno", "--logs", logs.0.to_str().unwrap()]);
+        replay(&capture.0, &args).await.unwrap();
+        serde_json::from_str(&std::fs::read_to_string(&logs.0).unwrap()).unwrap()
+    }
+
+    fn write_line(file: &mut File, timestamp: u128, direction: Direction, frame: &[u8]) {
+        write_device_line(file, timestamp, direction, frame, None);
+    }
+
+    fn write_device_line(
+        file: &mut File,
+        timestamp: u128,
+        direction: Direction,
+        frame: &[u8],
+        device: Option<&str>,
+    ) {
+        let raw_frame = RawFrame {
+            timestamp,
+            direction,
+            frame: frame.to_vec(),
+            device: device.map(String::from),
+        };
+        writeln!(file, "{}", serde_json::to_string(&raw_frame).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_reassembles_received_bytes() {
+        let capture = TempFile::new("fragments.jsonl");
+        let mut file = File::create(&capture.0).unwrap();
+        let request = build_read_request(DEFAULT_ADDRESS, TEMPS_REGISTER, TEMPS_COUNT);
+        let mut response = vec![DEFAULT_ADDRESS, 0x03, 0x0a, 0x00, 0x01, 0x00, 0xfa];
+        response.extend_from_slice(&[0x00; 6]);
+        response.extend_from_slice(&crc16(&response).to_le_bytes());
+
+        write_line(&mut file, 1000, Direction::Tx, &request);
+        write_line(&mut file, 1050, Direction::Rx, &response[..3]);
+        write_line(&mut file, 1060, Direction::Rx, &response[3..]);
+        // A fragment whose rest never arrived, dropped once the next response comes in
+        write_line(&mut file, 2000, Direction::Tx, &request);
+        write_line(&mut file, 2050, Direction::Rx, &response[..5]);
+        write_line(&mut file, 5000, Direction::Tx, &request);
+        write_line(&mut file, 5050, Direction::Rx, &response);
+
+        let data = replayed(&capture).await;
+        assert_eq!(data.len(), 2);
+        for data in data {
+            assert!(matches!(data.data, DataType::Temps { temps } if temps == [25.0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_drops_replies_captured_after_the_timeout() {
+        let capture = TempFile::new("late.jsonl");
+        let mut file = File::create(&capture.0).unwrap();
+        let request = build_read_request(DEFAULT_ADDRESS, TEMPS_REGISTER, TEMPS_COUNT);
+        let mut response = vec![DEFAULT_ADDRESS, 0x03, 0x0a, 0x00, 0x01, 0x00, 0xfa];
+        response.extend_from_slice(&[0x00; 6]);
+        response.extend_from_slice(&crc16(&response).to_le_bytes());
+
+        // Too late to be decoded live, so dropped on replay too
+        write_line(&mut file, 1000, Direction::Tx, &request);
+        write_line(&mut file, 3500, Direction::Rx, &response);
+        // Just inside the timeout
+        write_line(&mut file, 4000, Direction::Tx, &request);
+        write_line(&mut file, 5900, Direction::Rx, &response);
+
+        let data = replayed(&capture).await;
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].timestamp, 5900);
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_shared_devices_apart() {
+        let capture = TempFile::new("shared.jsonl");
+        let mut file = File::create(&capture.0).unwrap();
+        let request = build_read_request(DEFAULT_ADDRESS, TEMPS_REGISTER, TEMPS_COUNT);
+        let response = |temp: u8| {
+            let mut response = vec![DEFAULT_ADDRESS, 0x03, 0x0a, 0x00, 0x01, 0x00, temp];
+            response.extend_from_slice(&[0x00; 6]);
+            response.extend_from_slice(&crc16(&response).to_le_bytes());
+            response
+        };
+        let (first, second) = (response(0xfa), response(0x64));
+
+        // Both devices share an address, and their requests and fragments interleave
+        write_device_line(&mut file, 1000, Direction::Tx, &request, Some("first"));
+        write_device_line(&mut file, 1010, Direction::Tx, &request, Some("second"));
+        write_device_line(&mut file, 1050, Direction::Rx, &first[..3], Some("first"));
+        write_device_line(&mut file, 1055, Direction::Rx, &second[..5], Some("second"));
+        write_device_line(&mut file, 1060, Direction::Rx, &first[3..], Some("first"));
+        write_device_line(&mut file, 1065, Direction::Rx, &second[5..], Some("second"));
+
+        let data = replayed(&capture).await;
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].device.as_deref(), Some("first"));
+        assert!(matches!(&data[0].data, DataType::Temps { temps } if temps == &[25.0]));
+        assert_eq!(data[1].device.as_deref(), Some("second"));
+        assert!(matches!(&data[1].data, DataType::Temps { temps } if temps == &[10.0]));
+    }
+
+    #[tokio::test]
+    async fn captures_what_the_transport_carries() {
+        let capture = TempFile::new("simulator.jsonl");
+        let scenario = "name = \"test\"\ncharge = 50.0\nphases = []";
+        let mut transport =
+            SimulatorTransport::new(Simulator::new(Scenario::parse(scenario).unwrap()));
+        transport.capture(RawCapture::create(&capture.0, None).unwrap());
+        let mut connection = Connection::new(transport, Duration::from_millis(200), 0);
+        connection
+            .read_registers(DEFAULT_ADDRESS, TEMPS_REGISTER, TEMPS_COUNT)
+            .await
+            .unwrap();
+
+        let data = replayed(&capture).await;
+        assert_eq!(data.len(), 1);
+        assert!(matches!(&data[0].data, DataType::Temps { temps } if temps.len() == 4));
+    }
+}
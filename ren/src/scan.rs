@@ -0,0 +1,223 @@
+//! Lists the bluetooth devices advertising nearby, and picks out likely Renogy BT modules
+
+use btleplug::api::bleuuid::BleUuid;
+use btleplug::api::{Central, Peripheral as _, PeripheralProperties, ScanFilter};
+use btleplug::platform::{Adapter, Peripheral};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::ble::start_scan;
+use crate::clapper::Args;
+use crate::commands::hex_string;
+use crate::data::{RX_SERVICE, TX_SERVICE};
+
+/// Local name prefix of Renogy BT-1 and BT-2 modules
+pub const RENOGY_NAME_PREFIX: &str = "BT-TH-";
+
+/// A device seen while scanning, as advertised
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedDevice {
+    pub id: String,
+    pub address: String,
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+    /// Hex data, keyed by manufacturer ID
+    pub manufacturer_data: BTreeMap<u16, String>,
+    /// Hex data, keyed by service UUID
+    pub service_data: BTreeMap<String, String>,
+    pub services: Vec<String>,
+    /// Whether the name or services look like a Renogy BT module
+    pub renogy: bool,
+}
+
+impl ScannedDevice {
+    fn new(peripheral: &Peripheral, properties: PeripheralProperties) -> Self {
+        let renogy = is_renogy(&properties);
+        ScannedDevice {
+            id: peripheral.id().to_string(),
+            address: properties.address.to_string(),
+            local_name: properties.local_name,
+            rssi: properties.rssi,
+            manufacturer_data: properties
+                .manufacturer_data
+                .iter()
+                .map(|(id, data)| (*id, hex_string(data)))
+                .collect(),
+            service_data: properties
+                .service_data
+                .iter()
+                .map(|(uuid, data)| (uuid.to_short_string(), hex_string(data)))
+                .collect(),
+            services: properties
+                .services
+                .iter()
+                .map(|uuid| uuid.to_short_string())
+                .collect(),
+            renogy,
+        }
+    }
+}
+
+/// Whether the advertised name or services match a Renogy BT module
+pub fn is_renogy(properties: &PeripheralProperties) -> bool {
+    let named = properties
+        .local_name
+        .as_ref()
+        .is_some_and(|name| name.starts_with(RENOGY_NAME_PREFIX));
+    let advertised = properties.services.iter().any(|uuid| {
+        let uuid = uuid.to_string();
+        uuid == RX_SERVICE || uuid == TX_SERVICE
+    });
+    named || advertised
+}
+
+/// The devices to consider, from `--name-pattern`, `--service` and `--min-rssi`
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    pub name_pattern: Option<String>,
+    pub services: Vec<Uuid>,
+    pub min_rssi: Option<i16>,
+}
+
+impl DeviceFilter {
+    pub fn new(args: &Args) -> Self {
+        DeviceFilter {
+            name_pattern: args.name_pattern.clone(),
+            services: args.services.clone(),
+            min_rssi: args.min_rssi,
+        }
+    }
+
+    /// The filter for the adapter to apply while scanning
+    ///
+    /// Only services can be filtered by the adapter, so `matches` checks the rest afterwards.
+    pub fn scan_filter(&self) -> ScanFilter {
+        ScanFilter {
+            services: self.services.clone(),
+        }
+    }
+
+    /// Whether the advertised properties pass every filter given
+    pub fn matches(&self, properties: &PeripheralProperties) -> bool {
+        let name_matches = self.name_pattern.as_ref().is_none_or(|pattern| {
+            properties
+                .local_name
+                .as_ref()
+                .is_some_and(|name| glob_match(pattern, name))
+        });
+        let service_matches = self.services.is_empty()
+            || properties
+                .services
+                .iter()
+                .any(|uuid| self.services.contains(uuid));
+        let rssi_matches = self
+            .min_rssi
+            .is_none_or(|min_rssi| properties.rssi.is_some_and(|rssi| rssi >= min_rssi));
+        name_matches && service_matches && rssi_matches
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` matches any run of characters and `?` any one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it is matching up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Scans for `duration`, and returns every matching device seen, Renogy modules then strongest
+/// signal first
+pub async fn scan_devices(
+    central: &Adapter,
+    duration: Duration,
+    filter: &DeviceFilter,
+) -> Result<Vec<ScannedDevice>, Box<dyn Error + Send + Sync>> {
+    start_scan(central, filter.scan_filter()).await?;
+    time::sleep(duration).await;
+    central.stop_scan().await?;
+
+    let mut devices = Vec::new();
+    for peripheral in central.peripherals().await? {
+        if let Some(properties) = peripheral.properties().await? {
+            if filter.matches(&properties) {
+                devices.push(ScannedDevice::new(&peripheral, properties));
+            }
+        }
+    }
+    devices.sort_by_key(|device| (!device.renogy, std::cmp::Reverse(device.rssi)));
+    Ok(devices)
+}
+
+/// Prints the matching devices seen in `duration` as a table, or as JSON
+pub async fn print_scan(
+    central: &Adapter,
+    duration: Duration,
+    json: bool,
+    filter: &DeviceFilter,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !json {
+        println!("Scanning for {}s...", duration.as_secs_f32());
+    }
+    let devices = scan_devices(central, duration, filter).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+
+    println!(
+        "  {:<36}  {:<20}  {:>4}  {:<24}  {:<24}  Service data",
+        "ID", "Name", "RSSI", "Services", "Manufacturer data"
+    );
+    for device in &devices {
+        let services = device.services.join(", ");
+        let manufacturer_data = device
+            .manufacturer_data
+            .iter()
+            .map(|(id, data)| format!("{id:#06x}: {data}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let service_data = device
+            .service_data
+            .iter()
+            .map(|(uuid, data)| format!("{uuid}: {data}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!(
+            "{} {:<36}  {:<20}  {:>4}  {:<24}  {:<24}  {}",
+            if device.renogy { "*" } else { " " },
+            device.id,
+            device.local_name.as_deref().unwrap_or("-"),
+            device.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+            services,
+            manufacturer_data,
+            service_data
+        );
+    }
+    println!(
+        "{} device(s), {} likely Renogy (*)",
+        devices.len(),
+        devices.iter().filter(|device| device.renogy).count()
+    );
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Modbus RTU over an RS485 serial adapter, wired straight to the device's RS485 port
+
+use std::error::Error;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::reassembly::{FrameBuffer, FRAGMENT_TIMEOUT};
+use crate::record::{Direction, RawCapture};
+use crate::transport::Transport;
+
+/// A serial link to the device, 8 data bits, no parity and 1 stop bit
+pub struct SerialTransport {
+    path: String,
+    baud: u32,
+    /// `None` while the port is being reopened
+    port: Option<SerialStream>,
+    frames: FrameBuffer,
+    capture: Option<RawCapture>,
+}
+
+impl SerialTransport {
+    /// Opens the serial port at `path`, e.g. `/dev/ttyUSB0` or the slave side of a pseudo-terminal
+    pub fn open(path: &str, baud: u32) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let port = tokio_serial::new(path, baud).open_native_async()?;
+        Ok(SerialTransport {
+            path: path.to_string(),
+            baud,
+            port: Some(port),
+            frames: FrameBuffer::new(FRAGMENT_TIMEOUT),
+            capture: None,
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let port = self.port.as_mut().ok_or("Serial port closed")?;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.record(Direction::Tx, frame);
+        }
+        port.write_all(frame).await?;
+        Ok(())
+    }
+
+    /// Reassembles the next frame from however many reads it takes to arrive
+    async fn receive_frame(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; 256];
+        let port = self.port.as_mut()?;
+        loop {
+            if let Some(frame) = self.frames.next_frame() {
+                return Some(frame);
+            }
+            match port.read(&mut buffer).await {
+                Ok(0) | Err(_) => return None,
+                Ok(read) => {
+                    if let Some(capture) = self.capture.as_mut() {
+                        capture.record(Direction::Rx, &buffer[..read]);
+                    }
+                    self.frames.push(&buffer[..read]);
+                }
+            }
+        }
+    }
+
+    /// Reopens the port, e.g. after a USB adapter was unplugged and plugged back in
+    async fn reconnect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Drop the old port first, so its lock is released
+        self.port = None;
+        self.port = Some(tokio_serial::new(&self.path, self.baud).open_native_async()?);
+        self.frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(port) = self.port.as_mut() {
+            port.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    fn capture(&mut self, capture: RawCapture) {
+        self.capture = Some(capture);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::pty::openpty;
+    use nix::unistd::ttyname;
+
+    use super::*;
+    use crate::modbus::crc16;
+    use crate::transport::Connection;
+
+    /// Answers one read request on the master side of a pseudo-terminal, in two fragments
+    ///
+    /// Each register holds the low byte of its own number. The device is handed back, as closing
+    /// the master side hangs up the terminal and discards any reply not yet read.
+    fn respond_in_fragments(mut device: File) -> File {
+        let mut request = [0u8; 8];
+        device.read_exact(&mut request).unwrap();
+        let start = u16::from_be_bytes([request[2], request[3]]);
+        let count = u16::from_be_bytes([request[4], request[5]]);
+        let mut response = vec![request[0], request[1], (count * 2) as u8];
+        for register in start..start + count {
+            response.extend_from_slice(&(register & 0xff).to_be_bytes());
+        }
+        response.extend_from_slice(&crc16(&response).to_le_bytes());
+        device.write_all(&response[..3]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        device.write_all(&response[3..]).unwrap();
+        device
+    }
+
+    #[tokio::test]
+    async fn reads_registers_answered_in_fragments() {
+        let pty = openpty(None, None).unwrap();
+        let path = ttyname(&pty.slave).unwrap();
+        let responder = thread::spawn(move || respond_in_fragments(File::from(pty.master)));
+
+        let transport = SerialTransport::open(path.to_str().unwrap(), 9600).unwrap();
+        let mut connection = Connection::new(transport, Duration::from_secs(1), 0);
+        let registers = connection.read_registers(0x30, 0x1388, 3).await.unwrap();
+        assert_eq!(registers, [0x88, 0x89, 0x8a]);
+        responder.join().unwrap();
+    }
+}
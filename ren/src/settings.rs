@@ -0,0 +1,93 @@
+//! Charge controller settings that can be written over Modbus
+
+use clap::ValueEnum;
+
+/// A writable charge controller (Rover/Wanderer) setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Setting {
+    /// open, sealed, gel, lithium or custom
+    BatteryType,
+    /// Boost charging voltage [V], for a 12V system
+    BoostVoltage,
+    /// Float charging voltage [V], for a 12V system
+    FloatVoltage,
+    /// light-control, manual, debug, always-on, or 1-14 for light control with an N hour delay
+    LoadMode,
+}
+
+const BATTERY_TYPES: [(&str, u16); 5] = [
+    ("open", 1),
+    ("sealed", 2),
+    ("gel", 3),
+    ("lithium", 4),
+    ("custom", 5),
+];
+
+const LOAD_MODES: [(&str, u16); 4] = [
+    ("light-control", 0x00),
+    ("manual", 0x0f),
+    ("debug", 0x10),
+    ("always-on", 0x11),
+];
+
+impl Setting {
+    pub fn register(&self) -> u16 {
+        match self {
+            Setting::BatteryType => 0xe004,
+            Setting::BoostVoltage => 0xe008,
+            Setting::FloatVoltage => 0xe009,
+            Setting::LoadMode => 0xe01d,
+        }
+    }
+
+    /// Converts a value given on the command line to its raw register value
+    pub fn parse_value(&self, value: &str) -> Result<u16, String> {
+        match self {
+            Setting::BatteryType => lookup(&BATTERY_TYPES, value)
+                .ok_or_else(|| format!("Unknown battery type '{value}'")),
+            Setting::BoostVoltage | Setting::FloatVoltage => {
+                let volts: f32 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid voltage '{value}'"))?;
+                // Registers hold decivolts for a 12V system
+                if !(9.0..=17.0).contains(&volts) {
+                    return Err(format!("Voltage {volts}V is outside 9-17V"));
+                }
+                Ok((volts * 10.0).round() as u16)
+            }
+            Setting::LoadMode => match lookup(&LOAD_MODES, value) {
+                Some(mode) => Ok(mode),
+                None => match value.parse::<u16>() {
+                    Ok(hours @ 1..=14) => Ok(hours),
+                    _ => Err(format!("Unknown load mode '{value}'")),
+                },
+            },
+        }
+    }
+
+    /// Formats a raw register value for display
+    pub fn format_value(&self, raw: u16) -> String {
+        match self {
+            Setting::BatteryType => name_of(&BATTERY_TYPES, raw)
+                .map(String::from)
+                .unwrap_or_else(|| format!("unknown ({raw})")),
+            Setting::BoostVoltage | Setting::FloatVoltage => format!("{:.1}V", raw as f32 / 10.0),
+            Setting::LoadMode => match name_of(&LOAD_MODES, raw) {
+                Some(name) => name.to_string(),
+                None if (1..=14).contains(&raw) => format!("light-control, {raw}h delay"),
+                None => format!("unknown ({raw:#04x})"),
+            },
+        }
+    }
+}
+
+fn lookup(table: &[(&str, u16)], name: &str) -> Option<u16> {
+    table
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, raw)| *raw)
+}
+
+fn name_of(table: &[(&'static str, u16)], raw: u16) -> Option<&'static str> {
+    table.iter().find(|(_, r)| *r == raw).map(|(name, _)| *name)
+}
@@ -0,0 +1,331 @@
+//! Keeps a single Modbus request in flight and matches each response to it
+
+use std::time::{Duration, Instant};
+
+use crate::modbus::{
+    build_read_request, build_write_multiple_request, build_write_single_request, parse_response,
+    ModbusError, Response, READ_HOLDING_REGISTERS, WRITE_MULTIPLE_REGISTERS, WRITE_SINGLE_REGISTER,
+};
+
+/// How long to wait for a response before the request is abandoned
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A request that has been sent to the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub address: u8,
+    pub function: u8,
+    pub start: u16,
+    pub count: u16,
+    /// The values written, empty for reads
+    pub values: Vec<u16>,
+    pub sent_at: Instant,
+}
+
+impl Transaction {
+    pub fn read(address: u8, start: u16, count: u16) -> Self {
+        Transaction {
+            address,
+            function: READ_HOLDING_REGISTERS,
+            start,
+            count,
+            values: Vec::new(),
+            sent_at: Instant::now(),
+        }
+    }
+
+    /// A write of `values` starting at `start`
+    ///
+    /// A single value is sent with write single register (`0x06`), more with write multiple
+    /// registers (`0x10`).
+    pub fn write(address: u8, start: u16, values: &[u16]) -> Self {
+        let function = if values.len() == 1 {
+            WRITE_SINGLE_REGISTER
+        } else {
+            WRITE_MULTIPLE_REGISTERS
+        };
+        Transaction {
+            address,
+            function,
+            start,
+            count: values.len() as u16,
+            values: values.to_vec(),
+            sent_at: Instant::now(),
+        }
+    }
+
+    /// The transaction a request frame starts, as seen in a raw capture
+    pub fn from_request(frame: &[u8]) -> Option<Self> {
+        if frame.len() < 8 {
+            return None;
+        }
+        let address = frame[0];
+        let start = u16::from_be_bytes([frame[2], frame[3]]);
+        let count = u16::from_be_bytes([frame[4], frame[5]]);
+        match frame[1] {
+            READ_HOLDING_REGISTERS => Some(Transaction::read(address, start, count)),
+            WRITE_SINGLE_REGISTER => Some(Transaction::write(address, start, &[count])),
+            WRITE_MULTIPLE_REGISTERS => {
+                let values = frame
+                    .get(7..7 + usize::from(count) * 2)?
+                    .chunks_exact(2)
+                    .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                    .collect::<Vec<_>>();
+                Some(Transaction::write(address, start, &values))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn frame(&self) -> Vec<u8> {
+        match self.function {
+            WRITE_SINGLE_REGISTER => {
+                build_write_single_request(self.address, self.start, self.values[0]).to_vec()
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                build_write_multiple_request(self.address, self.start, &self.values)
+            }
+            _ => build_read_request(self.address, self.start, self.count).to_vec(),
+        }
+    }
+
+    /// Number of payload bytes a response to this request carries
+    ///
+    /// Write responses echo the start register and the value (or register count).
+    pub fn expected_byte_count(&self) -> usize {
+        if self.function == READ_HOLDING_REGISTERS {
+            usize::from(self.count) * 2
+        } else {
+            4
+        }
+    }
+
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        self.sent_at.elapsed() > timeout
+    }
+
+    /// The same request, sent again now
+    pub fn retry(&self) -> Self {
+        Transaction {
+            sent_at: Instant::now(),
+            ..self.clone()
+        }
+    }
+
+    /// Validates `frame` as the response to this request
+    pub fn resolve(&self, frame: &[u8]) -> Result<Response, ModbusError> {
+        let response = parse_response(frame, self.address, self.function)?;
+        if response.payload.len() != self.expected_byte_count() {
+            return Err(ModbusError::ByteCountMismatch {
+                expected: self.expected_byte_count(),
+                received: response.payload.len(),
+            });
+        }
+        if self.function != READ_HOLDING_REGISTERS {
+            let expected = match self.function {
+                WRITE_SINGLE_REGISTER => (self.start, self.values[0]),
+                _ => (self.start, self.count),
+            };
+            let received = (
+                u16::from_be_bytes([response.payload[0], response.payload[1]]),
+                u16::from_be_bytes([response.payload[2], response.payload[3]]),
+            );
+            if expected != received {
+                return Err(ModbusError::EchoMismatch { expected, received });
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Tracks the request in flight, so a late or missing reply cannot be decoded as a different request
+pub struct Transactions {
+    pending: Option<Transaction>,
+    timeout: Duration,
+}
+
+impl Transactions {
+    pub fn new(timeout: Duration) -> Self {
+        Transactions {
+            pending: None,
+            timeout,
+        }
+    }
+
+    /// Whether a new request can be sent
+    ///
+    /// A request that has been waiting longer than the timeout is dropped.
+    pub fn is_idle(&mut self) -> bool {
+        self.take_expired();
+        self.pending.is_none()
+    }
+
+    /// Drops the request in flight if it has been waiting longer than the timeout, and returns it
+    pub fn take_expired(&mut self) -> Option<Transaction> {
+        if !self.pending.as_ref()?.is_expired(self.timeout) {
+            return None;
+        }
+        self.expire()
+    }
+
+    /// Drops the request in flight as timed out, and returns it
+    ///
+    /// For a timeout measured by another clock, such as the timestamps of a raw capture.
+    pub fn expire(&mut self) -> Option<Transaction> {
+        let transaction = self.pending.take()?;
+        println!("Request for register {:#06x} timed out", transaction.start);
+        Some(transaction)
+    }
+
+    pub fn begin(&mut self, transaction: Transaction) {
+        self.pending = Some(transaction);
+    }
+
+    /// Matches `frame` to the request in flight
+    ///
+    /// A frame for a different address or register count leaves the request in flight. Any other
+    /// reply, valid or not, completes it.
+    pub fn complete(&mut self, frame: &[u8]) -> Result<(Transaction, Response), ModbusError> {
+        let transaction = match self.pending.take() {
+            Some(transaction) if !transaction.is_expired(self.timeout) => transaction,
+            _ => return Err(ModbusError::Unsolicited),
+        };
+
+        match transaction.resolve(frame) {
+            Ok(response) => Ok((transaction, response)),
+            Err(
+                e @ (ModbusError::AddressMismatch { .. } | ModbusError::ByteCountMismatch { .. }),
+            ) => {
+                self.pending = Some(transaction);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::crc16;
+
+    /// `bytes` followed by their CRC
+    fn with_crc(bytes: &[u8]) -> Vec<u8> {
+        let mut frame = bytes.to_vec();
+        frame.extend_from_slice(&crc16(bytes).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn completes_the_request_in_flight() {
+        let mut transactions = Transactions::new(REQUEST_TIMEOUT);
+        transactions.begin(Transaction::read(0x30, 0x1399, 2));
+        let (transaction, response) = transactions
+            .complete(&with_crc(&[0x30, 0x03, 0x04, 0x00, 0x01, 0x00, 0xfa]))
+            .unwrap();
+        assert_eq!(transaction.start, 0x1399);
+        assert_eq!(response.payload, [0x00, 0x01, 0x00, 0xfa]);
+        assert!(transactions.is_idle());
+    }
+
+    #[test]
+    fn late_reply_for_another_request_leaves_it_in_flight() {
+        let mut transactions = Transactions::new(REQUEST_TIMEOUT);
+        transactions.begin(Transaction::read(0x30, 0x1399, 2));
+        // The reply to an earlier, single register request
+        assert_eq!(
+            transactions.complete(&with_crc(&[0x30, 0x03, 0x02, 0x00, 0x01])),
+            Err(ModbusError::ByteCountMismatch {
+                expected: 4,
+                received: 2
+            })
+        );
+        assert!(!transactions.is_idle());
+        // And one from another battery
+        assert!(matches!(
+            transactions.complete(&with_crc(&[0x31, 0x03, 0x04, 0x00, 0x01, 0x00, 0xfa])),
+            Err(ModbusError::AddressMismatch { .. })
+        ));
+        assert!(transactions
+            .complete(&with_crc(&[0x30, 0x03, 0x04, 0x00, 0x01, 0x00, 0xfa]))
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_reply_completes_the_request() {
+        let mut transactions = Transactions::new(REQUEST_TIMEOUT);
+        transactions.begin(Transaction::read(0x30, 0x1399, 2));
+        let mut frame = with_crc(&[0x30, 0x03, 0x04, 0x00, 0x01, 0x00, 0xfa]);
+        frame[3] ^= 0xff;
+        assert!(matches!(
+            transactions.complete(&frame),
+            Err(ModbusError::BadCrc { .. })
+        ));
+        assert!(transactions.is_idle());
+    }
+
+    #[test]
+    fn reply_with_nothing_in_flight_is_unsolicited() {
+        let mut transactions = Transactions::new(REQUEST_TIMEOUT);
+        assert_eq!(
+            transactions.complete(&with_crc(&[0x30, 0x03, 0x02, 0x00, 0x01])),
+            Err(ModbusError::Unsolicited)
+        );
+    }
+
+    #[test]
+    fn reply_to_an_expired_request_is_unsolicited() {
+        let mut transactions = Transactions::new(Duration::ZERO);
+        transactions.begin(Transaction::read(0x30, 0x1399, 1));
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(
+            transactions.complete(&with_crc(&[0x30, 0x03, 0x02, 0x00, 0x01])),
+            Err(ModbusError::Unsolicited)
+        );
+        assert!(transactions.is_idle());
+    }
+
+    #[test]
+    fn expired_request_is_taken_once() {
+        let mut transactions = Transactions::new(Duration::ZERO);
+        let transaction = Transaction::read(0x30, 0x1399, 1);
+        transactions.begin(transaction.clone());
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(transactions.take_expired(), Some(transaction));
+        assert_eq!(transactions.take_expired(), None);
+    }
+
+    #[test]
+    fn rejects_write_echo_mismatch() {
+        let transaction = Transaction::write(0xff, 0x010a, &[1]);
+        assert_eq!(
+            transaction.resolve(&with_crc(&[0xff, 0x06, 0x01, 0x0a, 0x00, 0x00])),
+            Err(ModbusError::EchoMismatch {
+                expected: (0x010a, 1),
+                received: (0x010a, 0)
+            })
+        );
+        let transaction = Transaction::write(0xff, 0xe002, &[100, 200]);
+        assert!(transaction
+            .resolve(&with_crc(&[0xff, 0x10, 0xe0, 0x02, 0x00, 0x02]))
+            .is_ok());
+    }
+
+    #[test]
+    fn request_frame_round_trips() {
+        for transaction in [
+            Transaction::read(0x30, 0x13b2, 6),
+            Transaction::write(0xff, 0x010a, &[1]),
+            Transaction::write(0xff, 0xe002, &[100, 200]),
+        ] {
+            let parsed = Transaction::from_request(&transaction.frame()).unwrap();
+            assert_eq!(
+                Transaction {
+                    sent_at: transaction.sent_at,
+                    ..parsed
+                },
+                transaction
+            );
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! The link Modbus frames travel over, and the request/response client on top of it
+
+use std::error::Error;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time;
+
+use crate::modbus::{
+    ModbusError, Response, CHARGE_STATE_COUNT, CHARGE_STATE_REGISTER, LOAD_SWITCH_REGISTER,
+};
+use crate::record::RawCapture;
+use crate::transaction::Transaction;
+
+/// Carries whole Modbus frames to and from the device
+///
+/// Implemented by the BLE module link, so requests, decoding and scheduling run the same over any
+/// link, or none at all.
+pub trait Transport {
+    /// Writes a request frame
+    fn send_frame(
+        &mut self,
+        frame: &[u8],
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Waits for the next complete response frame, or `None` once the link is closed
+    fn receive_frame(&mut self) -> impl Future<Output = Option<Vec<u8>>> + Send;
+
+    /// Re-establishes a lost link, ready to carry frames again
+    fn reconnect(
+        &mut self,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Closes the link
+    fn close(&mut self) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Records the bytes carried from now on to `capture`, as they are written and read
+    fn capture(&mut self, capture: RawCapture);
+}
+
+/// Wait before the first reconnect attempt, doubled after every failed attempt
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// A device spoken to over a `Transport`
+pub struct Connection<T: Transport> {
+    transport: T,
+    /// How long to wait for each response
+    timeout: Duration,
+    /// How many times a request that timed out is sent again
+    retries: u32,
+}
+
+impl<T: Transport> Connection<T> {
+    pub fn new(transport: T, timeout: Duration, retries: u32) -> Self {
+        Connection {
+            transport,
+            timeout,
+            retries,
+        }
+    }
+
+    /// Writes the request frame of `transaction`
+    pub async fn send(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.transport.send_frame(&transaction.frame()).await
+    }
+
+    /// Waits for the next complete response frame
+    pub async fn next_frame(&mut self) -> Option<Vec<u8>> {
+        self.transport.receive_frame().await
+    }
+
+    pub async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.transport.close().await
+    }
+
+    /// Reconnects with exponential backoff until it succeeds, and returns the number of attempts
+    pub async fn reconnect(&mut self) -> u32 {
+        let mut delay = RECONNECT_DELAY;
+        let mut attempts = 0;
+        loop {
+            time::sleep(delay).await;
+            attempts += 1;
+            match self.transport.reconnect().await {
+                Ok(()) => return attempts,
+                Err(e) => {
+                    println!("Reconnect attempt {attempts} failed: {e}");
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Sends `transaction` and waits for its response, sending it again if it times out
+    pub async fn request(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        self.request_with_retries(transaction, self.retries).await
+    }
+
+    /// Sends `transaction` once and waits for its response
+    ///
+    /// For probing an address that may have no device behind it, where retrying only adds delay.
+    pub async fn probe(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        self.request_with_retries(transaction, 0).await
+    }
+
+    async fn request_with_retries(
+        &mut self,
+        transaction: &Transaction,
+        retries: u32,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let mut attempts = 0;
+        loop {
+            self.send(transaction).await?;
+            match time::timeout(self.timeout, self.receive_response(transaction)).await {
+                Ok(response) => return response,
+                Err(_) if attempts < retries => {
+                    attempts += 1;
+                    println!(
+                        "Request for register {:#06x} timed out, retrying ({attempts}/{retries})",
+                        transaction.start
+                    );
+                }
+                Err(_) => {
+                    return Err(format!(
+                        "No response for register {:#06x} after {} attempt(s)",
+                        transaction.start,
+                        attempts + 1
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+
+    /// Waits for the response to `transaction`, skipping late replies to earlier requests
+    async fn receive_response(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        loop {
+            let Some(frame) = self.next_frame().await else {
+                return Err("Connection closed".into());
+            };
+            match transaction.resolve(&frame) {
+                // A late reply to an earlier request
+                Err(
+                    ModbusError::AddressMismatch { .. } | ModbusError::ByteCountMismatch { .. },
+                ) => continue,
+                response => return Ok(response?),
+            }
+        }
+    }
+
+    /// Reads `count` holding registers from `start` on the device at `address`
+    pub async fn read_registers(
+        &mut self,
+        address: u8,
+        start: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .request(&Transaction::read(address, start, count))
+            .await?;
+
+        Ok(response
+            .payload
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect())
+    }
+
+    /// Writes `values` to the holding registers from `start` on the device at `address`
+    ///
+    /// The device echoes the write, so an error means it was not applied.
+    pub async fn write_registers(
+        &mut self,
+        address: u8,
+        start: u16,
+        values: &[u16],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.request(&Transaction::write(address, start, values))
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the load output of the charge controller at `address` is on
+    pub async fn load_state(&mut self, address: u8) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let state = self
+            .read_registers(address, CHARGE_STATE_REGISTER, CHARGE_STATE_COUNT)
+            .await?[0];
+        Ok(state & 0x8000 != 0)
+    }
+
+    /// Switches the load output of the charge controller at `address`, and returns its new state
+    ///
+    /// The controller only follows the switch when its load mode is manual.
+    pub async fn set_load(
+        &mut self,
+        address: u8,
+        on: bool,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.write_registers(address, LOAD_SWITCH_REGISTER, &[u16::from(on)])
+            .await?;
+        self.load_state(address).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::modbus::crc16;
+
+    /// Answers each request with the next replies in its script
+    struct MockTransport {
+        /// The frames sent back for each request in turn, none for a missed reply
+        script: VecDeque<Vec<Vec<u8>>>,
+        replies: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+        open: bool,
+    }
+
+    impl MockTransport {
+        fn new(script: Vec<Vec<Vec<u8>>>) -> Self {
+            MockTransport {
+                script: script.into(),
+                replies: VecDeque::new(),
+                sent: Vec::new(),
+                open: true,
+            }
+        }
+    }
+
+    impl Transport for MockTransport {
+        async fn send_frame(&mut self, frame: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.sent.push(frame.to_vec());
+            self.replies
+                .extend(self.script.pop_front().unwrap_or_default());
+            Ok(())
+        }
+
+        async fn receive_frame(&mut self) -> Option<Vec<u8>> {
+            if let Some(reply) = self.replies.pop_front() {
+                return Some(reply);
+            }
+            if !self.open {
+                return None;
+            }
+            std::future::pending().await
+        }
+
+        async fn reconnect(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.open = false;
+            Ok(())
+        }
+
+        fn capture(&mut self, _capture: RawCapture) {}
+    }
+
+    /// A read response from `address` carrying `data`
+    fn response(address: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![address, 0x03, data.len() as u8];
+        frame.extend_from_slice(data);
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    fn connection(script: Vec<Vec<Vec<u8>>>) -> Connection<MockTransport> {
+        Connection::new(MockTransport::new(script), Duration::from_millis(20), 2)
+    }
+
+    #[tokio::test]
+    async fn retries_request_that_timed_out() {
+        let mut connection = connection(vec![vec![], vec![response(0x30, &[0x00, 0x04])]]);
+        let registers = connection.read_registers(0x30, 0x1388, 1).await.unwrap();
+        assert_eq!(registers, [4]);
+        assert_eq!(connection.transport.sent.len(), 2);
+        assert_eq!(connection.transport.sent[0], connection.transport.sent[1]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_retries() {
+        let mut connection = connection(vec![]);
+        let error = connection
+            .read_registers(0x30, 0x1388, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No response for register 0x1388 after 3 attempt(s)"
+        );
+        assert_eq!(connection.transport.sent.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn probe_sends_once() {
+        let mut connection = connection(vec![]);
+        let transaction = Transaction::read(0x31, 0x13f6, 0x20);
+        assert!(connection.probe(&transaction).await.is_err());
+        assert_eq!(connection.transport.sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_late_replies() {
+        let mut connection = connection(vec![vec![
+            // Late replies to a single register request, and to another battery
+            response(0x30, &[0x00, 0x04]),
+            response(0x31, &[0x00, 0x04, 0x00, 0xfa]),
+            response(0x30, &[0x00, 0x04, 0x00, 0xfa]),
+        ]]);
+        let registers = connection.read_registers(0x30, 0x1399, 2).await.unwrap();
+        assert_eq!(registers, [4, 250]);
+        assert_eq!(connection.transport.sent.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_reply_is_an_error() {
+        let mut reply = response(0x30, &[0x00, 0x04]);
+        reply[3] ^= 0xff;
+        let mut connection = connection(vec![vec![reply]]);
+        let error = connection
+            .read_registers(0x30, 0x1388, 1)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("bad CRC"));
+    }
+
+    #[tokio::test]
+    async fn reports_closed_connection() {
+        let mut connection = connection(vec![]);
+        connection.close().await.unwrap();
+        let error = connection
+            .read_registers(0x30, 0x1388, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Connection closed");
+    }
+
+    #[tokio::test]
+    async fn reads_load_state() {
+        let mut connection = connection(vec![vec![response(0xff, &[0x80, 0x02])]]);
+        assert!(connection.load_state(0xff).await.unwrap());
+    }
+}
@@ -1,27 +1,48 @@
 use std::{
+    error::Error,
+    path::Path,
+    pin::Pin,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use btleplug::{
-    api::{bleuuid::BleUuid, Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter},
+    api::{
+        bleuuid::BleUuid, Central, CentralEvent, Characteristic, Manager as _, Peripheral as _,
+        ScanFilter, ValueNotification,
+    },
     platform::{Manager, Peripheral},
 };
 use tauri::Manager as _;
 use tokio::{select, time};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::{
     adapter::AdapterError,
     config::save_data_to_file,
-    data::{Data, DataView},
+    data::{BtModuleInfo, Data, DataType, DataView},
+    modbus::{ModbusError, Response, CONTROLLER_ADDRESS, DEFAULT_ADDRESS},
     peripheral::{
-        get_cell_volts, get_levels, get_temps, handle_data, subscribe_to_service, PeripheralError,
-        RequestType,
+        get_levels, get_load_state, handle_data, read_module_info, send_request, send_transaction,
+        set_load, subscribe_to_service, DeviceProfile, PeripheralError, RequestType,
     },
+    reassembly::{FrameBuffer, FRAGMENT_TIMEOUT},
     state::AppState,
+    transaction::{Transaction, Transactions, REQUEST_TIMEOUT},
 };
 
+/// How many times a request that timed out is sent again
+const RETRIES: u32 = 2;
+/// Replies missed in a row before the connection is presumed lost
+const MAX_MISSED: u32 = 3;
+/// No reply for this long means the module has gone silent
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Wait before the first reconnect attempt, doubled after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
 #[tauri::command]
 pub async fn refresh_bluetooth_adapters(
     state: tauri::State<'_, AppState>,
@@ -166,11 +187,12 @@ pub async fn search_for_peripherals(
     Ok(())
 }
 
+/// Connects to the peripheral, and returns its BT module info
 #[tauri::command]
 pub async fn connect_to_peripheral(
     state: tauri::State<'_, AppState>,
     id: &str,
-) -> Result<(), PeripheralError> {
+) -> Result<BtModuleInfo, PeripheralError> {
     let peripherals = state.peripherals.lock().unwrap().to_owned();
     for peripheral in peripherals {
         if peripheral.id().to_string() == id {
@@ -178,61 +200,179 @@ pub async fn connect_to_peripheral(
                 return Err(PeripheralError::Fail);
             } else {
                 peripheral.discover_services().await.unwrap();
+                let module_info = read_module_info(&peripheral).await;
                 *state.chosen_peripheral.lock().unwrap() = Some(peripheral);
-                return Ok(());
+                return Ok(module_info);
             }
         }
     }
-    Ok(())
+    Ok(BtModuleInfo::default())
 }
 
 #[tauri::command]
 pub async fn request_single_event(
     state: tauri::State<'_, AppState>,
 ) -> Result<String, PeripheralError> {
-    let peripheral = state.chosen_peripheral.lock().unwrap().to_owned().unwrap();
-    let rx_char = subscribe_to_service(&peripheral).await.unwrap();
+    let (transaction, response) = if state.simulator.lock().unwrap().is_some() {
+        simulate_request(&state, DEFAULT_ADDRESS, RequestType::Levels)?
+    } else {
+        let peripheral = state.chosen_peripheral.lock().unwrap().to_owned().unwrap();
+        let rx_char = subscribe_to_service(&peripheral).await.unwrap();
 
-    let mut notification_stream = peripheral.notifications().await.unwrap();
+        let mut notification_stream = peripheral.notifications().await.unwrap();
 
-    get_levels(&peripheral, &rx_char).await.unwrap();
-    if let Some(notification) = notification_stream.next().await {
-        let mut packet = notification.value;
-        let payload = DataView::new(&mut packet[3..]);
+        let transaction = get_levels(&peripheral, &rx_char).await.unwrap();
+        let response = await_response(&mut notification_stream, &transaction).await?;
+        (transaction, response)
+    };
+    let mut packet = response.payload;
+    let payload = DataView::new(&mut packet);
 
-        let system_time = std::time::SystemTime::now();
-        let timestamp = system_time
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
+    let system_time = std::time::SystemTime::now();
+    let timestamp = system_time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
 
-        let request_type = RequestType::GetLevels;
-        let data_type = handle_data(payload, &request_type);
-        let data = Data {
-            data: data_type,
-            timestamp,
-        };
+    let request_type = RequestType::Levels;
+    let data_type = handle_data(payload, &request_type);
+    let data = Data {
+        data: data_type,
+        timestamp,
+        address: Some(transaction.address),
+        device: None,
+    };
 
-        let data_json = serde_json::to_string(&data).unwrap();
-        return Ok(data_json);
-    } else {
-        return Err(PeripheralError::Fail);
-    }
+    let data_json = serde_json::to_string(&data).unwrap();
+    Ok(data_json)
+}
+
+/// Waits for the response to `transaction`, skipping late replies to earlier requests
+async fn await_response(
+    notification_stream: &mut (impl Stream<Item = ValueNotification> + Unpin),
+    transaction: &Transaction,
+) -> Result<Response, PeripheralError> {
+    let mut frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+    let response = time::timeout(REQUEST_TIMEOUT, async {
+        while let Some(notification) = notification_stream.next().await {
+            frames.push(&notification.value);
+            while let Some(frame) = frames.next_frame() {
+                match transaction.resolve(&frame) {
+                    Err(
+                        ModbusError::AddressMismatch { .. } | ModbusError::ByteCountMismatch { .. },
+                    ) => continue,
+                    response => return response.map_err(|_| PeripheralError::InvalidResponse),
+                }
+            }
+        }
+        Err(PeripheralError::Fail)
+    });
+    response.await.map_err(|_| PeripheralError::Fail)?
+}
+
+/// Reads whether the charge controller's load output is on
+///
+/// The controller is at `address`, or the usual controller address if not given.
+#[tauri::command]
+pub async fn get_load_output(
+    state: tauri::State<'_, AppState>,
+    address: Option<u8>,
+) -> Result<bool, PeripheralError> {
+    let address = address.unwrap_or(CONTROLLER_ADDRESS);
+    let peripheral = state
+        .chosen_peripheral
+        .lock()
+        .unwrap()
+        .to_owned()
+        .ok_or(PeripheralError::Fail)?;
+    let rx_char = subscribe_to_service(&peripheral)
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+    let mut notification_stream = peripheral
+        .notifications()
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+
+    let transaction = get_load_state(&peripheral, &rx_char, address)
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+    let mut packet = await_response(&mut notification_stream, &transaction)
+        .await?
+        .payload;
+    let load_state = DataView::new(&mut packet).get_uint16(0);
+    Ok(load_state & 0x8000 != 0)
+}
+
+/// Switches the charge controller's load output, and returns the state read back afterwards
+///
+/// The controller is at `address`, or the usual controller address if not given.
+#[tauri::command]
+pub async fn set_load_output(
+    state: tauri::State<'_, AppState>,
+    address: Option<u8>,
+    on: bool,
+) -> Result<bool, PeripheralError> {
+    let address = address.unwrap_or(CONTROLLER_ADDRESS);
+    let peripheral = state
+        .chosen_peripheral
+        .lock()
+        .unwrap()
+        .to_owned()
+        .ok_or(PeripheralError::Fail)?;
+    let rx_char = subscribe_to_service(&peripheral)
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+    let mut notification_stream = peripheral
+        .notifications()
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+
+    let transaction = set_load(&peripheral, &rx_char, address, on)
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+    await_response(&mut notification_stream, &transaction).await?;
+
+    let transaction = get_load_state(&peripheral, &rx_char, address)
+        .await
+        .map_err(|_| PeripheralError::Fail)?;
+    let mut packet = await_response(&mut notification_stream, &transaction)
+        .await?
+        .payload;
+    let load_state = DataView::new(&mut packet).get_uint16(0);
+    Ok(load_state & 0x8000 != 0)
 }
 
 #[tauri::command]
 pub async fn request_multiple_events(
     state: tauri::State<'_, AppState>,
     app: tauri::AppHandle,
+    profile: Option<DeviceProfile>,
 ) -> Result<(), String> {
+    let profile = profile.unwrap_or(DeviceProfile::Battery);
+    if state.simulator.lock().unwrap().is_some() {
+        return simulate_multiple_events(&state, app, profile).await;
+    }
     let path_resolver = app.path_resolver();
     let data_dir = path_resolver.app_data_dir().unwrap();
+    let file_path = data_dir.join("data").join("bt-data.json");
 
     let peripheral = state.chosen_peripheral.lock().unwrap().to_owned().unwrap();
-    let rx_char = subscribe_to_service(&peripheral).await.unwrap();
+    let mut rx_char = subscribe_to_service(&peripheral).await.unwrap();
 
     let mut notification_stream = peripheral.notifications().await.unwrap();
 
+    // Identify each device once, so the log shows which device the readings came from
+    let addresses = discover_addresses(
+        &peripheral,
+        &rx_char,
+        &mut notification_stream,
+        profile,
+        &app,
+        &file_path,
+    )
+    .await;
+    println!("Polling addresses: {:02x?}", addresses);
+
     let stop_recording = Arc::new(Mutex::new(false));
     let stop_recording_clone = stop_recording.clone();
     let _event_id = app.listen_global("stop-recording", move |_event| {
@@ -241,62 +381,346 @@ pub async fn request_multiple_events(
     });
 
     let mut count: u64 = 0;
-    let mut request_type = RequestType::GetLevels;
+    let mut transactions = Transactions::new(REQUEST_TIMEOUT);
+    let mut frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+    let mut last_frame = Instant::now();
+    // Replies missed in a row, and times the request in flight has been sent again
+    let mut missed: u32 = 0;
+    let mut retries: u32 = 0;
+
     let interval = 3;
     loop {
         if stop_recording.lock().unwrap().to_owned() {
             println!("Stopping recording");
             break;
         }
-        select! {
-            notification = notification_stream.next() => {
-                // get_levels(&peripheral, &rx_char).await.unwrap();
-                if let Some(notification) = notification {
-                    let mut packet = notification.value;
-                    let payload = DataView::new(&mut packet[3..]);
-
-                    let system_time = std::time::SystemTime::now();
-                    let timestamp = system_time
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-
-                    let data_type = handle_data(payload, &request_type);
-                    let data = Data {
-                        data: data_type,
-                        timestamp,
-                    };
-                    app.emit_all("Data", data.clone()).unwrap();
-                    // let data_json = serde_json::to_string(&data).unwrap();
-                    let file_path = data_dir.join("data").join("bt-data.json");
-                    if let Err(e) = save_data_to_file(data, file_path).await {
-                        println!("Error saving data: {:?}", e);
+        let lost: Option<String> = select! {
+            notification = notification_stream.next() => match notification {
+                Some(notification) => {
+                    frames.push(&notification.value);
+                    while let Some(frame) = frames.next_frame() {
+                        // Only a reply to the request in flight shows the link is healthy
+                        let completed = handle_frame(
+                            &frame,
+                            &mut transactions,
+                            profile,
+                            &app,
+                            &file_path,
+                        )
+                        .await;
+                        if completed {
+                            last_frame = Instant::now();
+                            missed = 0;
+                        }
                     }
-
+                    None
                 }
-
-            }
+                None => Some("Connection closed".to_string()),
+            },
             _ = time::sleep(Duration::from_secs(interval)) => {
                 count += 1;
 
-                if count % (interval * 20) == 0 {
-                    request_type = RequestType::GetTemps;
-                    if let Err(e) = get_temps(&peripheral, &rx_char).await {
-                        println!("Error getting temps: {:?}", e);
-                    }
-                } else if count % (interval * 10) == 0 {
-                    request_type = RequestType::GetCellVolts;
-                    if let Err(e) = get_cell_volts(&peripheral, &rx_char).await {
-                        println!("Error getting cell volts: {:?}", e);
+                if last_frame.elapsed() > SILENCE_TIMEOUT {
+                    Some(format!("No response for {}s", last_frame.elapsed().as_secs()))
+                } else if let Some(expired) = transactions.take_expired() {
+                    // Watchdog: too many missed replies in a row mean the link is unhealthy
+                    missed += 1;
+                    if missed >= MAX_MISSED {
+                        Some(format!("{missed} replies missed"))
+                    } else if retries < RETRIES {
+                        retries += 1;
+                        println!("Retrying ({retries}/{RETRIES})");
+                        let transaction = expired.retry();
+                        match send_transaction(&peripheral, &rx_char, &transaction).await {
+                            Ok(()) => transactions.begin(transaction),
+                            Err(e) => println!("Error sending request: {:?}", e),
+                        }
+                        None
+                    } else {
+                        None
                     }
+                } else if !transactions.is_idle() {
+                    None
                 } else {
-                    request_type = RequestType::GetLevels;
-                    if let Err(e) = get_levels(&peripheral, &rx_char).await {
-                        println!("Error getting levels: {:?}", e);
+                    // Take turns, so every device works through the whole schedule
+                    let device_count = addresses.len() as u64;
+                    let address = addresses[(count % device_count) as usize];
+                    let request_type = profile.scheduled_request(count / device_count, interval);
+                    retries = 0;
+                    let sent = send_request(&peripheral, &rx_char, address, request_type).await;
+                    match sent {
+                        Ok(transaction) => transactions.begin(transaction),
+                        Err(e) => println!("Error sending request: {:?}", e),
                     }
+                    None
                 }
             }
+        };
+
+        if let Some(reason) = lost {
+            println!("Connection lost: {reason}");
+            let data = Data {
+                data: DataType::Disconnected { reason },
+                timestamp: timestamp(),
+                address: None,
+                device: None,
+            };
+            emit_data(&app, data, &file_path).await;
+
+            let Some((attempts, resubscribed, notifications)) =
+                reconnect(&peripheral, &stop_recording).await
+            else {
+                println!("Stopping recording");
+                break;
+            };
+            rx_char = resubscribed;
+            notification_stream = notifications;
+            let data = Data {
+                data: DataType::Reconnected { attempts },
+                timestamp: timestamp(),
+                address: None,
+                device: None,
+            };
+            emit_data(&app, data, &file_path).await;
+
+            transactions = Transactions::new(REQUEST_TIMEOUT);
+            frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+            last_frame = Instant::now();
+            missed = 0;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a response frame against the request it answers, and sends it to the frontend
+///
+/// Returns whether the frame completed the request in flight.
+async fn handle_frame(
+    frame: &[u8],
+    transactions: &mut Transactions,
+    profile: DeviceProfile,
+    app: &tauri::AppHandle,
+    file_path: &Path,
+) -> bool {
+    let (transaction, response) = match transactions.complete(frame) {
+        Ok(completed) => completed,
+        Err(e) => {
+            println!("Error parsing response: {e}");
+            return false;
+        }
+    };
+    let Some(request_type) = profile.request_for_register(transaction.start) else {
+        println!("No decoder for register {:#06x}", transaction.start);
+        return true;
+    };
+    let mut packet = response.payload;
+    let payload = DataView::new(&mut packet);
+
+    let data = Data {
+        data: handle_data(payload, &request_type),
+        timestamp: timestamp(),
+        address: Some(transaction.address),
+        device: None,
+    };
+    emit_data(app, data, file_path).await;
+    true
+}
+
+/// Connects again with backoff until it succeeds, then resubscribes to notifications
+///
+/// Returns the number of attempts, the RX characteristic and the new notifications, or `None` if
+/// recording was stopped first.
+async fn reconnect(
+    peripheral: &Peripheral,
+    stop_recording: &Mutex<bool>,
+) -> Option<(u32, Characteristic, Notifications)> {
+    let mut delay = RECONNECT_DELAY;
+    let mut attempts = 0;
+    loop {
+        time::sleep(delay).await;
+        if stop_recording.lock().unwrap().to_owned() {
+            return None;
+        }
+        attempts += 1;
+        match resubscribe(peripheral).await {
+            Ok((rx_char, notifications)) => return Some((attempts, rx_char, notifications)),
+            Err(e) => {
+                println!("Reconnect attempt {attempts} failed: {e}");
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+/// Connects if the module dropped the connection, and subscribes to its notifications again
+async fn resubscribe(
+    peripheral: &Peripheral,
+) -> Result<(Characteristic, Notifications), Box<dyn Error>> {
+    if !peripheral.is_connected().await? {
+        peripheral.connect().await?;
+    }
+    peripheral.discover_services().await?;
+    let rx_char = subscribe_to_service(peripheral).await?;
+    let notifications = peripheral.notifications().await?;
+    Ok((rx_char, notifications))
+}
+
+fn timestamp() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+/// Identifies each device behind the BT module, and returns the addresses that answered
+///
+/// Addresses are probed upwards from the profile's default address, with a single attempt each.
+/// Linked batteries answer on consecutive addresses, so probing stops at the first one that does
+/// not.
+async fn discover_addresses(
+    peripheral: &Peripheral,
+    rx_char: &Characteristic,
+    notification_stream: &mut (impl Stream<Item = ValueNotification> + Unpin),
+    profile: DeviceProfile,
+    app: &tauri::AppHandle,
+    file_path: &Path,
+) -> Vec<u8> {
+    let request_type = profile.identify_request();
+    let mut addresses = Vec::new();
+    for address in profile.address_range() {
+        let transaction = match send_request(peripheral, rx_char, address, request_type).await {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                println!("Error sending request: {:?}", e);
+                break;
+            }
+        };
+        let response = match await_response(notification_stream, &transaction).await {
+            Ok(response) => response,
+            Err(e) => {
+                println!("No device at address {address:#04x}: {:?}", e);
+                break;
+            }
+        };
+        let mut packet = response.payload;
+        let data = Data {
+            data: handle_data(DataView::new(&mut packet), &request_type),
+            timestamp: timestamp(),
+            address: Some(address),
+            device: None,
+        };
+        emit_data(app, data, file_path).await;
+        addresses.push(address);
+    }
+
+    if addresses.is_empty() {
+        println!("No device answered. Polling the default address.");
+        addresses.push(profile.default_address());
+    }
+    addresses
+}
+
+/// Sends `data` to the frontend, and appends it to the log file
+async fn emit_data(app: &tauri::AppHandle, data: Data, file_path: &Path) {
+    app.emit_all("Data", data.clone()).unwrap();
+    if let Err(e) = save_data_to_file(data, file_path.to_path_buf()).await {
+        println!("Error saving data: {:?}", e);
+    }
+}
+
+/// Sends a request to the simulated battery from `--simulate`, and validates its response
+fn simulate_request(
+    state: &AppState,
+    address: u8,
+    request_type: RequestType,
+) -> Result<(Transaction, Response), PeripheralError> {
+    let (start, count) = request_type.registers();
+    let transaction = Transaction::read(address, start, count);
+    let frame = state
+        .simulator
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|simulator| simulator.respond(&transaction.frame()))
+        .ok_or(PeripheralError::Fail)?;
+    let response = transaction
+        .resolve(&frame)
+        .map_err(|_| PeripheralError::InvalidResponse)?;
+    Ok((transaction, response))
+}
+
+/// Polls the simulated batteries from `--simulate` on the same schedule as real ones
+///
+/// Addresses are discovered as with a real BT module, so every linked battery is polled in turn.
+async fn simulate_multiple_events(
+    state: &AppState,
+    app: tauri::AppHandle,
+    profile: DeviceProfile,
+) -> Result<(), String> {
+    let path_resolver = app.path_resolver();
+    let data_dir = path_resolver.app_data_dir().unwrap();
+    let file_path = data_dir.join("data").join("bt-data.json");
+
+    let request_type = profile.identify_request();
+    let mut addresses = Vec::new();
+    for address in profile.address_range() {
+        let Ok((_, response)) = simulate_request(state, address, request_type) else {
+            println!("No device at address {address:#04x}");
+            break;
+        };
+        let mut packet = response.payload;
+        let data = Data {
+            data: handle_data(DataView::new(&mut packet), &request_type),
+            timestamp: timestamp(),
+            address: Some(address),
+            device: None,
+        };
+        emit_data(&app, data, &file_path).await;
+        addresses.push(address);
+    }
+    if addresses.is_empty() {
+        addresses.push(profile.default_address());
+    }
+    println!("Polling addresses: {:02x?}", addresses);
+
+    let stop_recording = Arc::new(Mutex::new(false));
+    let stop_recording_clone = stop_recording.clone();
+    let _event_id = app.listen_global("stop-recording", move |_event| {
+        println!("Recording stopped");
+        *stop_recording_clone.lock().unwrap() = true;
+    });
+
+    let mut count: u64 = 0;
+    let interval = 3;
+    loop {
+        if stop_recording.lock().unwrap().to_owned() {
+            println!("Stopping recording");
+            break;
         }
+        time::sleep(Duration::from_secs(interval)).await;
+        count += 1;
+
+        // Take turns, so every battery works through the whole schedule
+        let device_count = addresses.len() as u64;
+        let address = addresses[(count % device_count) as usize];
+        let request_type = profile.scheduled_request(count / device_count, interval);
+        let (transaction, response) = match simulate_request(state, address, request_type) {
+            Ok(completed) => completed,
+            Err(e) => {
+                println!("Error simulating request: {:?}", e);
+                continue;
+            }
+        };
+        let mut packet = response.payload;
+        let payload = DataView::new(&mut packet);
+
+        let data = Data {
+            data: handle_data(payload, &request_type),
+            timestamp: timestamp(),
+            address: Some(transaction.address),
+            device: None,
+        };
+        emit_data(&app, data, &file_path).await;
     }
     Ok(())
 }
@@ -7,10 +7,22 @@ use serde::{Deserialize, Serialize};
 pub struct Data {
     pub data: DataType,
     pub timestamp: u128,
+    /// Modbus address of the device the data came from
+    #[serde(default)]
+    pub address: Option<u8>,
+    /// Name of the monitored BT module the data came from, when monitoring several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
 }
 
 impl Debug for Data {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(device) = self.device.as_ref() {
+            write!(f, "[{}] ", device)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, "[{:#04x}] ", address)?;
+        }
         match &self.data {
             DataType::Levels {
                 current,
@@ -31,6 +43,136 @@ impl Debug for Data {
             DataType::Temps { temps } => {
                 write!(f, "Temperature: {:?}", temps)
             }
+            DataType::Health {
+                cycle_count,
+                design_capacity,
+                charge_volt_limit,
+                discharge_volt_limit,
+                charge_current_limit,
+                discharge_current_limit,
+            } => {
+                write!(
+                    f,
+                    "Cycles: {}; Design Capacity: {}; Charge Limits: {}V {}A; Discharge Limits: {}V {}A;",
+                    cycle_count,
+                    design_capacity,
+                    charge_volt_limit,
+                    charge_current_limit,
+                    discharge_volt_limit,
+                    discharge_current_limit
+                )
+            }
+            DataType::Status {
+                flags,
+                cell_volt_alarms,
+                cell_temp_alarms,
+            } => {
+                write!(
+                    f,
+                    "Status: {:?}; Cell Voltage Alarms: {:?}; Cell Temperature Alarms: {:?};",
+                    flags, cell_volt_alarms, cell_temp_alarms
+                )
+            }
+            DataType::Charging {
+                battery_soc,
+                battery_volt,
+                charging_current,
+                controller_temp,
+                battery_temp,
+                load_volt: _,
+                load_current: _,
+                load_power,
+                pv_volt,
+                pv_current,
+                pv_power,
+            } => {
+                write!(
+                    f,
+                    "PV: {}W {}V {}A; Battery: {}V {}A SoC: {}; Load: {}W; Temperature: {} {};",
+                    pv_power,
+                    pv_volt,
+                    pv_current,
+                    battery_volt,
+                    charging_current,
+                    battery_soc,
+                    load_power,
+                    controller_temp,
+                    battery_temp
+                )
+            }
+            DataType::DcCharging {
+                battery_soc,
+                battery_volt,
+                charging_current,
+                controller_temp,
+                battery_temp,
+                alternator_volt,
+                alternator_current,
+                alternator_power,
+                pv_volt,
+                pv_current,
+                pv_power,
+            } => {
+                write!(
+                    f,
+                    "Alternator: {}W {}V {}A; PV: {}W {}V {}A; Battery: {}V {}A SoC: {}; Temperature: {} {};",
+                    alternator_power,
+                    alternator_volt,
+                    alternator_current,
+                    pv_power,
+                    pv_volt,
+                    pv_current,
+                    battery_volt,
+                    charging_current,
+                    battery_soc,
+                    controller_temp,
+                    battery_temp
+                )
+            }
+            DataType::DailyStats {
+                generation,
+                consumption,
+                charging_amp_hours,
+                discharging_amp_hours,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Generated: {}Wh; Consumed: {}Wh; Charged: {}Ah; Discharged: {}Ah;",
+                    generation, consumption, charging_amp_hours, discharging_amp_hours
+                )
+            }
+            DataType::ChargeState {
+                load_on,
+                charging_state,
+            } => {
+                write!(
+                    f,
+                    "Load On: {}; Charging State: {:?};",
+                    load_on, charging_state
+                )
+            }
+            DataType::ChargeStage { charging_state } => {
+                write!(f, "Charging State: {:?};", charging_state)
+            }
+            DataType::DeviceInfo {
+                model,
+                serial_number,
+                firmware_version,
+                software_version,
+                manufacturer,
+            } => {
+                write!(
+                    f,
+                    "Model: {}; Serial: {}; Firmware: {}; Software: {}; Manufacturer: {};",
+                    model, serial_number, firmware_version, software_version, manufacturer
+                )
+            }
+            DataType::BtModule(info) => write!(f, "BT Module: {};", info),
+            DataType::Disconnected { reason } => write!(f, "Disconnected: {};", reason),
+            DataType::Reconnected { attempts } => {
+                write!(f, "Reconnected after {} attempt(s);", attempts)
+            }
         }
     }
 }
@@ -49,6 +191,329 @@ pub enum DataType {
     Temps {
         temps: Vec<f32>,
     },
+    Health {
+        cycle_count: u16,
+        design_capacity: f32,
+        charge_volt_limit: f32,
+        discharge_volt_limit: f32,
+        charge_current_limit: f32,
+        discharge_current_limit: f32,
+    },
+    Status {
+        /// Protection, warning and charge/discharge state flags that are set
+        flags: Vec<StatusFlag>,
+        cell_volt_alarms: Vec<CellAlarm>,
+        cell_temp_alarms: Vec<CellAlarm>,
+    },
+    Charging {
+        battery_soc: f32,
+        battery_volt: f32,
+        charging_current: f32,
+        controller_temp: f32,
+        battery_temp: f32,
+        load_volt: f32,
+        load_current: f32,
+        load_power: f32,
+        pv_volt: f32,
+        pv_current: f32,
+        pv_power: f32,
+    },
+    DcCharging {
+        battery_soc: f32,
+        battery_volt: f32,
+        /// Total charging current from the alternator and solar inputs
+        charging_current: f32,
+        controller_temp: f32,
+        battery_temp: f32,
+        alternator_volt: f32,
+        alternator_current: f32,
+        alternator_power: f32,
+        pv_volt: f32,
+        pv_current: f32,
+        pv_power: f32,
+    },
+    DailyStats {
+        min_battery_volt: f32,
+        max_battery_volt: f32,
+        max_charging_current: f32,
+        max_discharging_current: f32,
+        max_charging_power: f32,
+        max_discharging_power: f32,
+        charging_amp_hours: f32,
+        discharging_amp_hours: f32,
+        /// Energy generated today [Wh]
+        generation: f32,
+        /// Energy consumed by the load today [Wh]
+        consumption: f32,
+    },
+    ChargeState {
+        load_on: bool,
+        charging_state: ChargingState,
+    },
+    ChargeStage {
+        charging_state: ChargingState,
+    },
+    DeviceInfo {
+        model: String,
+        serial_number: String,
+        firmware_version: String,
+        software_version: String,
+        manufacturer: String,
+    },
+    /// The BT module itself, from its Device Information Service
+    BtModule(BtModuleInfo),
+    /// The connection to the BT module was lost, and is being re-established
+    Disconnected {
+        reason: String,
+    },
+    Reconnected {
+        attempts: u32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargingState {
+    Deactivated,
+    Activated,
+    Mppt,
+    Equalizing,
+    Boost,
+    Floating,
+    CurrentLimiting,
+    /// DC-DC chargers only: charging from the alternator
+    DcMode,
+    Unknown(u8),
+}
+
+impl From<u8> for ChargingState {
+    fn from(state: u8) -> Self {
+        match state {
+            0 => ChargingState::Deactivated,
+            1 => ChargingState::Activated,
+            2 => ChargingState::Mppt,
+            3 => ChargingState::Equalizing,
+            4 => ChargingState::Boost,
+            5 => ChargingState::Floating,
+            6 => ChargingState::CurrentLimiting,
+            8 => ChargingState::DcMode,
+            state => ChargingState::Unknown(state),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFlag {
+    // Status 1 (register 0x13f2): protections
+    ModuleUnderVoltage,
+    ChargeOverTemp,
+    ChargeUnderTemp,
+    DischargeOverTemp,
+    DischargeUnderTemp,
+    DischargeOverCurrent1,
+    ChargeOverCurrent1,
+    CellOverVoltage,
+    CellUnderVoltage,
+    ModuleOverVoltage,
+    DischargeOverCurrent2,
+    ChargeOverCurrent2,
+    UsingBatteryModulePower,
+    DischargeMosfetOn,
+    ChargeMosfetOn,
+    ShortCircuit,
+    // Status 2 (register 0x13f3): warnings and heater
+    CellLowVoltage,
+    CellHighVoltage,
+    ModuleLowVoltage,
+    ModuleHighVoltage,
+    ChargeLowTemp,
+    ChargeHighTemp,
+    DischargeLowTemp,
+    DischargeHighTemp,
+    ChargeOverCurrent,
+    DischargeOverCurrent,
+    HeaterOn,
+    EffectiveChargeCurrent,
+    EffectiveDischargeCurrent,
+    // Charge/discharge status (register 0x13f5)
+    FullChargeRequest,
+    ChargeImmediately,
+    DischargeEnabled,
+    ChargeEnabled,
+}
+
+/// Flags for each bit of status 1, from bit 0
+pub const STATUS_1_FLAGS: [Option<StatusFlag>; 16] = [
+    Some(StatusFlag::ModuleUnderVoltage),
+    Some(StatusFlag::ChargeOverTemp),
+    Some(StatusFlag::ChargeUnderTemp),
+    Some(StatusFlag::DischargeOverTemp),
+    Some(StatusFlag::DischargeUnderTemp),
+    Some(StatusFlag::DischargeOverCurrent1),
+    Some(StatusFlag::ChargeOverCurrent1),
+    Some(StatusFlag::CellOverVoltage),
+    Some(StatusFlag::CellUnderVoltage),
+    Some(StatusFlag::ModuleOverVoltage),
+    Some(StatusFlag::DischargeOverCurrent2),
+    Some(StatusFlag::ChargeOverCurrent2),
+    Some(StatusFlag::UsingBatteryModulePower),
+    Some(StatusFlag::DischargeMosfetOn),
+    Some(StatusFlag::ChargeMosfetOn),
+    Some(StatusFlag::ShortCircuit),
+];
+
+/// Flags for each bit of status 2, from bit 0
+pub const STATUS_2_FLAGS: [Option<StatusFlag>; 16] = [
+    Some(StatusFlag::CellLowVoltage),
+    Some(StatusFlag::CellHighVoltage),
+    Some(StatusFlag::ModuleLowVoltage),
+    Some(StatusFlag::ModuleHighVoltage),
+    Some(StatusFlag::ChargeLowTemp),
+    Some(StatusFlag::ChargeHighTemp),
+    Some(StatusFlag::DischargeLowTemp),
+    Some(StatusFlag::DischargeHighTemp),
+    Some(StatusFlag::ChargeOverCurrent),
+    Some(StatusFlag::DischargeOverCurrent),
+    None,
+    None,
+    None,
+    Some(StatusFlag::HeaterOn),
+    Some(StatusFlag::EffectiveChargeCurrent),
+    Some(StatusFlag::EffectiveDischargeCurrent),
+];
+
+/// Flags for each bit of the charge/discharge status, from bit 0
+pub const CHARGE_DISCHARGE_FLAGS: [Option<StatusFlag>; 16] = [
+    None,
+    None,
+    None,
+    Some(StatusFlag::FullChargeRequest),
+    None,
+    Some(StatusFlag::ChargeImmediately),
+    Some(StatusFlag::DischargeEnabled),
+    Some(StatusFlag::ChargeEnabled),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+/// The flags in `table` whose bit is set in `word`
+pub fn flags_from_bits(word: u16, table: &[Option<StatusFlag>; 16]) -> Vec<StatusFlag> {
+    table
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| word & (1 << bit) != 0)
+        .filter_map(|(_, flag)| *flag)
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    BelowLowerLimit,
+    AboveUpperLimit,
+    OtherFault,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CellAlarm {
+    /// 1-based cell number
+    pub cell: u8,
+    pub alarm: Alarm,
+}
+
+/// The cells in alarm, from a 32-bit field holding two bits per cell, cell 1 in the lowest bits
+pub fn cell_alarms_from_bits(bits: u32) -> Vec<CellAlarm> {
+    (0..16)
+        .filter_map(|cell| {
+            let alarm = match (bits >> (cell * 2)) & 0b11 {
+                0b01 => Alarm::BelowLowerLimit,
+                0b10 => Alarm::AboveUpperLimit,
+                0b11 => Alarm::OtherFault,
+                _ => return None,
+            };
+            Some(CellAlarm {
+                cell: cell as u8 + 1,
+                alarm,
+            })
+        })
+        .collect()
+}
+
+/// Device Information Service strings of the BT module, read once after connecting
+///
+/// A characteristic the module does not expose, or that cannot be read, is `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BtModuleInfo {
+    /// Hex bytes
+    pub system_id: Option<String>,
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub software_revision: Option<String>,
+    pub manufacturer_name: Option<String>,
+    /// Hex bytes: vendor ID source, vendor ID, product ID and product version
+    pub pnp_id: Option<String>,
+}
+
+impl BtModuleInfo {
+    /// Stores the value read from the Device Information characteristic `uuid`
+    pub fn set(&mut self, uuid: &str, value: &[u8]) {
+        let text = || {
+            Some(
+                String::from_utf8_lossy(value)
+                    .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                    .to_string(),
+            )
+        };
+        let hex = || {
+            Some(
+                value
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        };
+        match uuid {
+            SYSTEM_ID_CHARACTERISTIC => self.system_id = hex(),
+            MODEL_NUMBER_CHARACTERISTIC => self.model_number = text(),
+            SERIAL_NUMBER_CHARACTERISTIC => self.serial_number = text(),
+            FIRMWARE_REVISION_CHARACTERISTIC => self.firmware_revision = text(),
+            HARDWARE_REVISION_CHARACTERISTIC => self.hardware_revision = text(),
+            SOFTWARE_REVISION_CHARACTERISTIC => self.software_revision = text(),
+            MANUFACTURER_NAME_CHARACTERISTIC => self.manufacturer_name = text(),
+            PNP_ID_CHARACTERISTIC => self.pnp_id = hex(),
+            _ => {}
+        }
+    }
+}
+
+impl std::fmt::Display for BtModuleInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let fields = [
+            ("Manufacturer", &self.manufacturer_name),
+            ("Model", &self.model_number),
+            ("Serial", &self.serial_number),
+            ("Firmware", &self.firmware_revision),
+            ("Hardware", &self.hardware_revision),
+            ("Software", &self.software_revision),
+            ("System ID", &self.system_id),
+            ("PnP ID", &self.pnp_id),
+        ];
+        let fields = fields
+            .iter()
+            .filter_map(|(name, value)| Some(format!("{name}: {}", value.as_ref()?)))
+            .collect::<Vec<_>>();
+        if fields.is_empty() {
+            return write!(f, "no device information");
+        }
+        write!(f, "{}", fields.join("; "))
+    }
 }
 
 pub struct DataView<'a> {
@@ -60,6 +525,10 @@ impl<'a> DataView<'a> {
         DataView { buffer }
     }
 
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
     pub fn set_uint16(&mut self, offset: usize, value: u16) {
         self.buffer[offset] = (value >> 8) as u8;
         self.buffer[offset + 1] = value as u8;
@@ -79,6 +548,16 @@ impl<'a> DataView<'a> {
             | ((self.buffer[offset + 2] as u32) << 8)
             | self.buffer[offset + 3] as u32
     }
+
+    /// Reads `len` bytes as ASCII, dropping the NUL and space padding
+    pub fn get_ascii(&self, offset: usize, len: usize) -> String {
+        self.buffer[offset..offset + len]
+            .iter()
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string()
+    }
 }
 
 pub const CHARACTERISTICS: [&str; 19] = [
@@ -117,51 +596,12 @@ pub const TX_CHARACTERISTIC: &str = CHARACTERISTICS[17];
 pub const RX_SERVICE: &str = SERVICES[2];
 pub const TX_SERVICE: &str = SERVICES[3];
 
-// Characteristic { uuid: 00002a00-0000-1000-8000-00805f9b34fb, service_uuid: 00001800-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a01-0000-1000-8000-00805f9b34fb, service_uuid: 00001800-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a04-0000-1000-8000-00805f9b34fb, service_uuid: 00001800-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a23-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ | WRITE_WITHOUT_RESPONSE | WRITE, descriptors: {} },
-// Characteristic { uuid: 00002a24-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a25-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a26-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a27-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a28-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a29-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a2a-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 00002a50-0000-1000-8000-00805f9b34fb, service_uuid: 0000180a-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {} },
-// Characteristic { uuid: 0000ffd1-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, properties: READ | WRITE_WITHOUT_RESPONSE | WRITE, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd1-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: 0000ffd2-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, properties: READ | NOTIFY, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd2-0000-1000-8000-00805f9b34fb }, Descriptor { uuid: 00002902-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd2-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: 0000ffd3-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, properties: WRITE, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd3-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: 0000ffd4-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, properties: READ, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd4-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: 0000ffd5-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, properties: READ | WRITE, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000ffd5-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: 0000fff1-0000-1000-8000-00805f9b34fb, service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb, properties: READ | NOTIFY, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000fff1-0000-1000-8000-00805f9b34fb }, Descriptor { uuid: 00002902-0000-1000-8000-00805f9b34fb, service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb, characteristic_uuid: 0000fff1-0000-1000-8000-00805f9b34fb }} },
-// Characteristic { uuid: f000ffd1-0451-4000-b000-000000000000, service_uuid: f000ffd0-0451-4000-b000-000000000000, properties: WRITE_WITHOUT_RESPONSE | WRITE, descriptors: {Descriptor { uuid: 00002901-0000-1000-8000-00805f9b34fb, service_uuid: f000ffd0-0451-4000-b000-000000000000, characteristic_uuid: f000ffd1-0451-4000-b000-000000000000 }} }}
-
-// service_uuid: 00001800-0000-1000-8000-00805f9b34fb
-// service_uuid: 00001800-0000-1000-8000-00805f9b34fb
-// service_uuid: 00001800-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000180a-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000ffd0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb
-// service_uuid: 0000fff0-0000-1000-8000-00805f9b34fb
-// service_uuid: f000ffd0-0451-4000-b000-000000000000
-// service_uuid: f000ffd0-0451-4000-b000-000000000000
+pub const DEVICE_INFO_SERVICE: &str = SERVICES[1];
+pub const SYSTEM_ID_CHARACTERISTIC: &str = CHARACTERISTICS[3];
+pub const MODEL_NUMBER_CHARACTERISTIC: &str = CHARACTERISTICS[4];
+pub const SERIAL_NUMBER_CHARACTERISTIC: &str = CHARACTERISTICS[5];
+pub const FIRMWARE_REVISION_CHARACTERISTIC: &str = CHARACTERISTICS[6];
+pub const HARDWARE_REVISION_CHARACTERISTIC: &str = CHARACTERISTICS[7];
+pub const SOFTWARE_REVISION_CHARACTERISTIC: &str = CHARACTERISTICS[8];
+pub const MANUFACTURER_NAME_CHARACTERISTIC: &str = CHARACTERISTICS[9];
+pub const PNP_ID_CHARACTERISTIC: &str = CHARACTERISTICS[11];
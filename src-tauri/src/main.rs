@@ -5,20 +5,43 @@ mod adapter;
 mod commands;
 mod config;
 mod data;
+mod modbus;
 mod peripheral;
+mod reassembly;
+mod simulator;
 mod state;
+mod transaction;
+
+use std::path::PathBuf;
+
+use clap::Parser;
 
 use commands::{
-    connect_to_adapter, connect_to_peripheral, refresh_bluetooth_adapters, request_multiple_events,
-    request_single_event, search_for_peripherals,
+    connect_to_adapter, connect_to_peripheral, get_load_output, refresh_bluetooth_adapters,
+    request_multiple_events, request_single_event, search_for_peripherals, set_load_output,
 };
+use simulator::{Scenario, Simulator};
 use state::AppState;
 
 use config::get_config;
 
+#[derive(Parser, Debug)]
+struct Args {
+    /// Scenario file to simulate a battery from, instead of connecting to one
+    #[arg(long)]
+    simulate: Option<PathBuf>,
+}
+
 fn main() {
+    let args = Args::parse();
+    let state = AppState::default();
+    if let Some(path) = args.simulate {
+        let scenario = Scenario::load(&path).expect("scenario file to be valid");
+        *state.simulator.lock().unwrap() = Some(Simulator::new(scenario));
+    }
+
     tauri::Builder::default()
-        .manage(AppState::default())
+        .manage(state)
         .invoke_handler(tauri::generate_handler![
             refresh_bluetooth_adapters,
             connect_to_adapter,
@@ -26,6 +49,8 @@ fn main() {
             connect_to_peripheral,
             request_single_event,
             request_multiple_events,
+            get_load_output,
+            set_load_output,
             // Config
             get_config
         ])
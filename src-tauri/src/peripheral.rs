@@ -1,4 +1,4 @@
-use std::error::Error;
+use std::{error::Error, ops::RangeInclusive};
 
 use btleplug::{
     api::{CharPropFlags, Characteristic, Peripheral as _, WriteType},
@@ -6,10 +6,45 @@ use btleplug::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::data::{
-    DataType, DataView, RX_CHARACTERISTIC, RX_SERVICE, TX_CHARACTERISTIC, TX_SERVICE,
+use crate::{
+    data::{
+        cell_alarms_from_bits, flags_from_bits, BtModuleInfo, ChargingState, DataType, DataView,
+        CHARGE_DISCHARGE_FLAGS, DEVICE_INFO_SERVICE, RX_CHARACTERISTIC, RX_SERVICE, STATUS_1_FLAGS,
+        STATUS_2_FLAGS, TX_CHARACTERISTIC, TX_SERVICE,
+    },
+    modbus::{
+        CELL_VOLTS_COUNT, CELL_VOLTS_REGISTER, CHARGE_STATE_COUNT, CHARGE_STATE_REGISTER,
+        CHARGING_COUNT, CHARGING_REGISTER, CONTROLLER_ADDRESS, CONTROLLER_INFO_COUNT,
+        CONTROLLER_INFO_REGISTER, DAILY_STATS_COUNT, DAILY_STATS_REGISTER, DEFAULT_ADDRESS,
+        DEVICE_INFO_COUNT, DEVICE_INFO_REGISTER, HEALTH_COUNT, HEALTH_REGISTER, LEVELS_COUNT,
+        LEVELS_REGISTER, LOAD_SWITCH_REGISTER, STATUS_COUNT, STATUS_REGISTER, TEMPS_COUNT,
+        TEMPS_REGISTER,
+    },
+    transaction::Transaction,
 };
 
+/// Reads the Device Information Service, after services have been discovered
+pub async fn read_module_info(peripheral: &Peripheral) -> BtModuleInfo {
+    let mut info = BtModuleInfo::default();
+    let Some(service) = peripheral
+        .services()
+        .into_iter()
+        .find(|s| s.uuid.to_string() == DEVICE_INFO_SERVICE)
+    else {
+        return info;
+    };
+    for characteristic in &service.characteristics {
+        if !characteristic.properties.contains(CharPropFlags::READ) {
+            continue;
+        }
+        match peripheral.read(characteristic).await {
+            Ok(value) => info.set(&characteristic.uuid.to_string(), &value),
+            Err(e) => println!("Error reading {}: {}", characteristic.uuid, e),
+        }
+    }
+    info
+}
+
 pub async fn subscribe_to_service(
     peripheral: &Peripheral,
 ) -> Result<Characteristic, Box<dyn Error>> {
@@ -18,7 +53,7 @@ pub async fn subscribe_to_service(
     let rx_service = services
         .into_iter()
         .find(|s| s.uuid.to_string() == RX_SERVICE)
-        .unwrap();
+        .ok_or("RX_SERVICE not found")?;
     let rx_char = rx_service
         .characteristics
         .into_iter()
@@ -26,29 +61,45 @@ pub async fn subscribe_to_service(
             c.uuid.to_string() == RX_CHARACTERISTIC
                 && c.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE)
         })
-        .expect("RX_CHARACTERISTIC not found");
+        .ok_or("RX_CHARACTERISTIC not found")?;
 
     let services = peripheral.services();
     let tx_service = services
         .into_iter()
         .find(|s| s.uuid.to_string() == TX_SERVICE)
-        .unwrap();
+        .ok_or("TX_SERVICE not found")?;
     let tx_char = tx_service
         .characteristics
         .into_iter()
         .find(|c| {
             c.uuid.to_string() == TX_CHARACTERISTIC && c.properties.contains(CharPropFlags::NOTIFY)
         })
-        .expect("TX_CHARACTERISTIC not found");
+        .ok_or("TX_CHARACTERISTIC not found")?;
 
     // Start notifications on TX_CHARACTERISTIC
     peripheral.subscribe(&tx_char).await?;
     Ok(rx_char)
 }
 
+/// The count in the first register of a count-prefixed block, capped at the registers read after it
+///
+/// A device reporting more than the block holds would otherwise be read past the payload.
+fn reported_count(payload: &DataView, what: &str) -> u16 {
+    if payload.len() < 2 {
+        return 0;
+    }
+    let available = (payload.len() / 2 - 1) as u16;
+    let reported = payload.get_uint16(0);
+    if reported > available {
+        println!("Device reported {reported} {what}, but only {available} were read");
+        return available;
+    }
+    reported
+}
+
 pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
     match request_type {
-        RequestType::GetLevels => {
+        RequestType::Levels => {
             let current = f32::from(payload.get_int16(0)) / 100.0;
             let volt = f32::from(payload.get_uint16(2)) / 10.0;
             let charge_level = payload.get_uint32(4) as f32 / 1000.0;
@@ -65,16 +116,16 @@ pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
                 capacity,
             }
         }
-        RequestType::GetCellVolts => {
-            let num_cells = payload.get_uint16(0);
+        RequestType::CellVolts => {
+            let num_cells = reported_count(&payload, "cells");
             let volts = (0..num_cells)
                 .map(|i| f32::from(payload.get_uint16((1 + i) as usize * 2)) / 10.0)
                 .collect::<Vec<_>>();
             // println!("Cell voltages: {:?}", volts);
             DataType::CellVolts { cell_volts: volts }
         }
-        RequestType::GetTemps => {
-            let num_sensors = payload.get_uint16(0);
+        RequestType::Temps => {
+            let num_sensors = reported_count(&payload, "temperature sensors");
             let mut temps = Vec::new();
             for i in 0..num_sensors {
                 let temp = f32::from(payload.get_int16((1 + i) as usize * 2)) / 10.0;
@@ -83,68 +134,347 @@ pub fn handle_data(payload: DataView, request_type: &RequestType) -> DataType {
             // println!("Temperatures: {:?}", temps);
             DataType::Temps { temps }
         }
+        RequestType::Health => {
+            // The module total capacity is the rated capacity, not what the cells hold today
+            let design_capacity = payload.get_uint32(0) as f32 / 1000.0;
+            let cycle_count = payload.get_uint16(4);
+            let charge_volt_limit = f32::from(payload.get_uint16(6)) / 10.0;
+            let discharge_volt_limit = f32::from(payload.get_uint16(8)) / 10.0;
+            let charge_current_limit = f32::from(payload.get_uint16(10)) / 100.0;
+            let discharge_current_limit = f32::from(payload.get_int16(12)) / 100.0;
+            DataType::Health {
+                cycle_count,
+                design_capacity,
+                charge_volt_limit,
+                discharge_volt_limit,
+                charge_current_limit,
+                discharge_current_limit,
+            }
+        }
+        RequestType::Status => {
+            let mut flags = flags_from_bits(payload.get_uint16(12), &STATUS_1_FLAGS);
+            flags.extend(flags_from_bits(payload.get_uint16(14), &STATUS_2_FLAGS));
+            flags.extend(flags_from_bits(
+                payload.get_uint16(18),
+                &CHARGE_DISCHARGE_FLAGS,
+            ));
+            DataType::Status {
+                flags,
+                cell_volt_alarms: cell_alarms_from_bits(payload.get_uint32(0)),
+                cell_temp_alarms: cell_alarms_from_bits(payload.get_uint32(4)),
+            }
+        }
+        RequestType::DeviceInfo => {
+            // Registers 0x13f6-0x1415: serial number, manufacture version, mainline (firmware)
+            // version, protocol version, model, software version, manufacturer
+            DataType::DeviceInfo {
+                serial_number: payload.get_ascii(0, 16),
+                firmware_version: payload.get_ascii(18, 4),
+                model: payload.get_ascii(24, 16),
+                software_version: payload.get_ascii(40, 4),
+                manufacturer: payload.get_ascii(44, 20),
+            }
+        }
+        RequestType::ControllerInfo => {
+            // Registers 0x000c-0x0019: model, software version, hardware version, serial number
+            // Controllers have no firmware version or manufacturer registers, so the hardware
+            // version stands in for the firmware
+            DataType::DeviceInfo {
+                model: payload.get_ascii(0, 16),
+                software_version: version_string(&payload, 16),
+                firmware_version: version_string(&payload, 20),
+                serial_number: payload.get_uint32(24).to_string(),
+                manufacturer: String::new(),
+            }
+        }
+        RequestType::Charging => {
+            let temps = payload.get_uint16(6);
+            DataType::Charging {
+                battery_soc: f32::from(payload.get_uint16(0)),
+                battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+                charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+                controller_temp: sign_magnitude((temps >> 8) as u8),
+                battery_temp: sign_magnitude(temps as u8),
+                load_volt: f32::from(payload.get_uint16(8)) / 10.0,
+                load_current: f32::from(payload.get_uint16(10)) / 100.0,
+                load_power: f32::from(payload.get_uint16(12)),
+                pv_volt: f32::from(payload.get_uint16(14)) / 10.0,
+                pv_current: f32::from(payload.get_uint16(16)) / 100.0,
+                pv_power: f32::from(payload.get_uint16(18)),
+            }
+        }
+        RequestType::DcCharging => {
+            let temps = payload.get_uint16(6);
+            DataType::DcCharging {
+                battery_soc: f32::from(payload.get_uint16(0)),
+                battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+                charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+                controller_temp: sign_magnitude((temps >> 8) as u8),
+                battery_temp: sign_magnitude(temps as u8),
+                alternator_volt: f32::from(payload.get_uint16(8)) / 10.0,
+                alternator_current: f32::from(payload.get_uint16(10)) / 100.0,
+                alternator_power: f32::from(payload.get_uint16(12)),
+                pv_volt: f32::from(payload.get_uint16(14)) / 10.0,
+                pv_current: f32::from(payload.get_uint16(16)) / 100.0,
+                pv_power: f32::from(payload.get_uint16(18)),
+            }
+        }
+        RequestType::DailyStats => DataType::DailyStats {
+            min_battery_volt: f32::from(payload.get_uint16(0)) / 10.0,
+            max_battery_volt: f32::from(payload.get_uint16(2)) / 10.0,
+            max_charging_current: f32::from(payload.get_uint16(4)) / 100.0,
+            max_discharging_current: f32::from(payload.get_uint16(6)) / 100.0,
+            max_charging_power: f32::from(payload.get_uint16(8)),
+            max_discharging_power: f32::from(payload.get_uint16(10)),
+            charging_amp_hours: f32::from(payload.get_uint16(12)),
+            discharging_amp_hours: f32::from(payload.get_uint16(14)),
+            generation: f32::from(payload.get_uint16(16)),
+            consumption: f32::from(payload.get_uint16(18)),
+        },
+        RequestType::ChargeState => {
+            let state = payload.get_uint16(0);
+            DataType::ChargeState {
+                load_on: state & 0x8000 != 0,
+                charging_state: ChargingState::from(state as u8),
+            }
+        }
+        RequestType::ChargeStage => DataType::ChargeStage {
+            charging_state: ChargingState::from(payload.get_uint16(0) as u8),
+        },
     }
 }
 
-pub async fn get_levels(
+/// Decodes a temperature byte whose top bit is the sign
+fn sign_magnitude(byte: u8) -> f32 {
+    let magnitude = f32::from(byte & 0x7f);
+    if byte & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Formats a version register pair, `0x00`, major, minor, patch, as `V1.2.3`
+fn version_string(payload: &DataView, offset: usize) -> String {
+    let version = payload.get_uint32(offset);
+    format!(
+        "V{}.{}.{}",
+        (version >> 16) as u8,
+        (version >> 8) as u8,
+        version as u8
+    )
+}
+
+pub async fn send_request(
     peripheral: &Peripheral,
     rx_char: &Characteristic,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x13b2);
-    view.set_uint16(4, 0x0006);
-    view.set_uint16(6, 0x654a);
-
-    peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
-        .await?;
-    Ok(())
+    address: u8,
+    request_type: RequestType,
+) -> Result<Transaction, Box<dyn Error>> {
+    let (start, count) = request_type.registers();
+    let transaction = Transaction::read(address, start, count);
+    send_transaction(peripheral, rx_char, &transaction).await?;
+    Ok(transaction)
 }
 
-pub async fn get_cell_volts(
+/// Writes the request frame of `transaction`, e.g. to send it again after it timed out
+pub async fn send_transaction(
     peripheral: &Peripheral,
     rx_char: &Characteristic,
+    transaction: &Transaction,
 ) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x1388);
-    view.set_uint16(4, 0x0011);
-    view.set_uint16(6, 0x0549);
-
     peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
+        .write(rx_char, &transaction.frame(), WriteType::WithResponse)
         .await?;
     Ok(())
 }
 
-pub async fn get_temps(
+pub async fn get_levels(
     peripheral: &Peripheral,
     rx_char: &Characteristic,
-) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0u8; 8];
-    let mut view = DataView::new(&mut buffer);
-    view.set_uint16(0, 0x3003);
-    view.set_uint16(2, 0x1399);
-    view.set_uint16(4, 0x0005);
-    view.set_uint16(6, 0x5543);
+) -> Result<Transaction, Box<dyn Error>> {
+    send_request(peripheral, rx_char, DEFAULT_ADDRESS, RequestType::Levels).await
+}
+
+/// Requests the charge state register, whose high bit is the load output state
+pub async fn get_load_state(
+    peripheral: &Peripheral,
+    rx_char: &Characteristic,
+    address: u8,
+) -> Result<Transaction, Box<dyn Error>> {
+    send_request(peripheral, rx_char, address, RequestType::ChargeState).await
+}
 
+/// Switches the charge controller's load output on or off
+///
+/// The controller only follows the switch when its load mode is manual.
+pub async fn set_load(
+    peripheral: &Peripheral,
+    rx_char: &Characteristic,
+    address: u8,
+    on: bool,
+) -> Result<Transaction, Box<dyn Error>> {
+    let transaction = Transaction::write(address, LOAD_SWITCH_REGISTER, &[u16::from(on)]);
     peripheral
-        .write(rx_char, &buffer, WriteType::WithResponse)
+        .write(rx_char, &transaction.frame(), WriteType::WithResponse)
         .await?;
-    Ok(())
+    Ok(transaction)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestType {
-    GetLevels,
-    GetCellVolts,
-    GetTemps,
+    Levels,
+    CellVolts,
+    Temps,
+    Health,
+    Status,
+    DeviceInfo,
+    ControllerInfo,
+    Charging,
+    DailyStats,
+    ChargeState,
+    DcCharging,
+    ChargeStage,
+}
+
+impl RequestType {
+    /// The start register and register count the request reads
+    pub fn registers(&self) -> (u16, u16) {
+        match self {
+            RequestType::Levels => (LEVELS_REGISTER, LEVELS_COUNT),
+            RequestType::CellVolts => (CELL_VOLTS_REGISTER, CELL_VOLTS_COUNT),
+            RequestType::Temps => (TEMPS_REGISTER, TEMPS_COUNT),
+            RequestType::Health => (HEALTH_REGISTER, HEALTH_COUNT),
+            RequestType::Status => (STATUS_REGISTER, STATUS_COUNT),
+            RequestType::DeviceInfo => (DEVICE_INFO_REGISTER, DEVICE_INFO_COUNT),
+            RequestType::ControllerInfo => (CONTROLLER_INFO_REGISTER, CONTROLLER_INFO_COUNT),
+            RequestType::Charging => (CHARGING_REGISTER, CHARGING_COUNT),
+            RequestType::DailyStats => (DAILY_STATS_REGISTER, DAILY_STATS_COUNT),
+            RequestType::ChargeState => (CHARGE_STATE_REGISTER, CHARGE_STATE_COUNT),
+            RequestType::DcCharging => (CHARGING_REGISTER, CHARGING_COUNT),
+            RequestType::ChargeStage => (CHARGE_STATE_REGISTER, CHARGE_STATE_COUNT),
+        }
+    }
+}
+
+/// The kind of Renogy device behind the BT module, which decides the register map to poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DeviceProfile {
+    /// Renogy smart lithium battery
+    Battery,
+    /// Rover/Wanderer solar charge controller
+    ChargeController,
+    /// DCC series DC-DC charger with MPPT solar input
+    DcCharger,
+}
+
+impl DeviceProfile {
+    /// Modbus address the device answers on when it is the only one behind the BT module
+    pub fn default_address(&self) -> u8 {
+        match self {
+            DeviceProfile::Battery => DEFAULT_ADDRESS,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => CONTROLLER_ADDRESS,
+        }
+    }
+
+    /// Addresses that linked devices of this kind answer on
+    pub fn address_range(&self) -> RangeInclusive<u8> {
+        match self {
+            DeviceProfile::Battery => DEFAULT_ADDRESS..=DEFAULT_ADDRESS + 0x0f,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => {
+                CONTROLLER_ADDRESS..=CONTROLLER_ADDRESS
+            }
+        }
+    }
+
+    /// Every request the device understands
+    pub fn requests(&self) -> &'static [RequestType] {
+        match self {
+            DeviceProfile::Battery => &[
+                RequestType::Levels,
+                RequestType::CellVolts,
+                RequestType::Temps,
+                RequestType::Health,
+                RequestType::Status,
+                RequestType::DeviceInfo,
+            ],
+            DeviceProfile::ChargeController => &[
+                RequestType::ControllerInfo,
+                RequestType::Charging,
+                RequestType::DailyStats,
+                RequestType::ChargeState,
+            ],
+            DeviceProfile::DcCharger => &[
+                RequestType::ControllerInfo,
+                RequestType::DcCharging,
+                RequestType::DailyStats,
+                RequestType::ChargeStage,
+            ],
+        }
+    }
+
+    /// The request that identifies the device, sent once after connecting
+    pub fn identify_request(&self) -> RequestType {
+        match self {
+            DeviceProfile::Battery => RequestType::DeviceInfo,
+            DeviceProfile::ChargeController | DeviceProfile::DcCharger => {
+                RequestType::ControllerInfo
+            }
+        }
+    }
+
+    /// The request to send on poll number `count`
+    pub fn scheduled_request(&self, count: u64, interval: u64) -> RequestType {
+        match self {
+            // Prioritise health > temps > cell volts > status > levels
+            DeviceProfile::Battery => {
+                if count.is_multiple_of(interval * 40) {
+                    RequestType::Health
+                } else if count.is_multiple_of(interval * 20) {
+                    RequestType::Temps
+                } else if count.is_multiple_of(interval * 10) {
+                    RequestType::CellVolts
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::Status
+                } else {
+                    RequestType::Levels
+                }
+            }
+            // Prioritise daily stats > charge state > charging
+            DeviceProfile::ChargeController => {
+                if count.is_multiple_of(interval * 10) {
+                    RequestType::DailyStats
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::ChargeState
+                } else {
+                    RequestType::Charging
+                }
+            }
+            // Prioritise daily stats > charge stage > charging
+            DeviceProfile::DcCharger => {
+                if count.is_multiple_of(interval * 10) {
+                    RequestType::DailyStats
+                } else if count.is_multiple_of(interval * 5) {
+                    RequestType::ChargeStage
+                } else {
+                    RequestType::DcCharging
+                }
+            }
+        }
+    }
+
+    /// The request whose register block starts at `start`
+    pub fn request_for_register(&self, start: u16) -> Option<RequestType> {
+        self.requests()
+            .iter()
+            .find(|request| request.registers().0 == start)
+            .copied()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum PeripheralError {
     Fail,
     UnableToUseAdapter,
+    InvalidResponse,
 }
@@ -0,0 +1,113 @@
+//! Reassembles Modbus frames split across several BLE notifications or serial reads
+//!
+//! A notification carries at most the negotiated MTU, so longer responses (cell voltages, device
+//! info) arrive in pieces. A serial read returns whatever bytes have arrived so far.
+
+use std::time::{Duration, Instant};
+
+use crate::modbus::frame_len;
+
+/// How long a partial frame waits for its next fragment before it is dropped
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Buffers notification bytes until the header's byte count and the CRC have arrived
+pub struct FrameBuffer {
+    buffer: Vec<u8>,
+    last_fragment: Instant,
+    timeout: Duration,
+}
+
+impl FrameBuffer {
+    pub fn new(timeout: Duration) -> Self {
+        FrameBuffer {
+            buffer: Vec::new(),
+            last_fragment: Instant::now(),
+            timeout,
+        }
+    }
+
+    /// Appends the bytes of a notification
+    ///
+    /// A partial frame older than the timeout is dropped first, so a lost fragment cannot corrupt
+    /// the next response.
+    pub fn push(&mut self, fragment: &[u8]) {
+        self.push_at(fragment, Instant::now());
+    }
+
+    /// Appends bytes that arrived at `at`, e.g. when replaying a capture
+    pub fn push_at(&mut self, fragment: &[u8], at: Instant) {
+        if !self.buffer.is_empty()
+            && at.saturating_duration_since(self.last_fragment) > self.timeout
+        {
+            println!(
+                "Dropping stale partial frame of {} bytes",
+                self.buffer.len()
+            );
+            self.buffer.clear();
+        }
+        self.buffer.extend_from_slice(fragment);
+        self.last_fragment = at;
+    }
+
+    /// Takes the next complete frame, if all of its bytes have arrived
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let len = frame_len(&self.buffer)?;
+        if self.buffer.len() < len {
+            return None;
+        }
+        Some(self.buffer.drain(..len).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modbus::{build_write_single_request, crc16};
+
+    /// A read response carrying `data`
+    fn response(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x30, 0x03, data.len() as u8];
+        frame.extend_from_slice(data);
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn reassembles_frame_split_across_fragments() {
+        let frame = response(&[0x00, 0x04, 0x00, 0xfa, 0x00, 0xfb]);
+        let mut frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+        frames.push(&frame[..2]);
+        assert_eq!(frames.next_frame(), None);
+        frames.push(&frame[2..6]);
+        assert_eq!(frames.next_frame(), None);
+        frames.push(&frame[6..]);
+        assert_eq!(frames.next_frame(), Some(frame));
+        assert_eq!(frames.next_frame(), None);
+    }
+
+    #[test]
+    fn splits_several_frames_in_one_fragment() {
+        let first = response(&[0x00, 0x04]);
+        // A write single register echo has no byte count
+        let second = build_write_single_request(0x30, 0x010a, 1).to_vec();
+        let third = response(&[0x00, 0x04, 0x00, 0xfa]);
+        let mut frames = FrameBuffer::new(FRAGMENT_TIMEOUT);
+        frames.push(&[first.as_slice(), &second, &third[..4]].concat());
+        assert_eq!(frames.next_frame(), Some(first));
+        assert_eq!(frames.next_frame(), Some(second));
+        assert_eq!(frames.next_frame(), None);
+        frames.push(&third[4..]);
+        assert_eq!(frames.next_frame(), Some(third));
+    }
+
+    #[test]
+    fn drops_stale_partial_frame() {
+        let frame = response(&[0x00, 0x04, 0x00, 0xfa]);
+        let mut frames = FrameBuffer::new(Duration::from_millis(10));
+        frames.push(&frame[..4]);
+        std::thread::sleep(Duration::from_millis(20));
+        frames.push(&frame);
+        assert_eq!(frames.next_frame(), Some(frame));
+        assert_eq!(frames.next_frame(), None);
+    }
+}
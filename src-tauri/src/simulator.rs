@@ -0,0 +1,406 @@
+//! Simulated Renogy smart lithium batteries that answer Modbus reads, following a scenario
+//!
+//! Scenarios are TOML files listing phases a battery goes through, e.g. charging, discharging,
+//! cells drifting apart or overheating, and any batteries linked to it. See the
+//! `ren/scenarios` directory for examples.
+
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::modbus::{
+    crc16, CELL_VOLTS_COUNT, CELL_VOLTS_REGISTER, DEFAULT_ADDRESS, DEVICE_INFO_COUNT,
+    DEVICE_INFO_REGISTER, EXCEPTION_FLAG, HEALTH_COUNT, HEALTH_REGISTER, LEVELS_COUNT,
+    LEVELS_REGISTER, READ_HOLDING_REGISTERS, STATUS_COUNT, STATUS_REGISTER, TEMPS_COUNT,
+    TEMPS_REGISTER,
+};
+
+/// Exception code for a function the battery does not support
+const ILLEGAL_FUNCTION: u8 = 0x01;
+/// Exception code for registers outside the battery's register map
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+
+/// Cell voltages at which the cell voltage alarms trip [V]
+const CELL_VOLT_LIMITS: (f32, f32) = (2.5, 3.65);
+/// Temperature above which the high temperature warnings are raised [°C]
+const HIGH_TEMP: f32 = 50.0;
+/// Temperature above which charging and discharging are stopped [°C]
+const OVER_TEMP: f32 = 60.0;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    /// The battery the BT module is plugged into
+    #[serde(flatten)]
+    pub battery: Battery,
+    /// Batteries linked to the first, as behind a BT-2 hub
+    #[serde(default)]
+    pub linked: Vec<Battery>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Battery {
+    /// Modbus address the battery answers on, by default the one after the battery before it
+    pub address: Option<u8>,
+    /// Rated capacity [Ah]
+    #[serde(default = "default_capacity")]
+    pub capacity: f32,
+    /// Charge held at the start [Ah]
+    pub charge: f32,
+    /// Cells in series, 1 to 16
+    #[serde(default = "default_cells")]
+    pub cells: u16,
+    /// Cell temperature at the start [°C]
+    #[serde(default = "default_temp")]
+    pub temp: f32,
+    #[serde(default)]
+    pub cycle_count: u16,
+    /// Played in order, after which the battery rests in its final state
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Phase {
+    /// How long the phase lasts [seconds]
+    pub duration: f32,
+    /// Battery current [A], negative when discharging
+    pub current: f32,
+    /// Change in cell temperature [°C per minute]
+    #[serde(default)]
+    pub temp_rate: f32,
+    /// How fast each cell drifts from the others [V per minute], one entry per cell
+    #[serde(default)]
+    pub cell_drift: Vec<f32>,
+}
+
+fn default_capacity() -> f32 {
+    100.0
+}
+
+fn default_cells() -> u16 {
+    4
+}
+
+fn default_temp() -> f32 {
+    25.0
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Scenario::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(scenario: &str) -> Result<Self, Box<dyn Error>> {
+        let mut scenario: Scenario = toml::from_str(scenario)?;
+        // The cell voltage block holds the cell count and one register per cell
+        let max_cells = CELL_VOLTS_COUNT - 1;
+        let mut address = DEFAULT_ADDRESS;
+        let mut addresses = Vec::new();
+        for battery in scenario.batteries_mut() {
+            if !(1..=max_cells).contains(&battery.cells) {
+                return Err(format!(
+                    "Scenario has {} cells, but a battery has 1 to {max_cells}",
+                    battery.cells
+                )
+                .into());
+            }
+            address = *battery.address.get_or_insert(address);
+            if addresses.contains(&address) {
+                return Err(format!("Scenario has two batteries at address {address:#04x}").into());
+            }
+            addresses.push(address);
+            address = address.wrapping_add(1);
+        }
+        Ok(scenario)
+    }
+
+    /// The first battery, then the linked ones
+    pub fn batteries(&self) -> impl Iterator<Item = &Battery> {
+        std::iter::once(&self.battery).chain(&self.linked)
+    }
+
+    fn batteries_mut(&mut self) -> impl Iterator<Item = &mut Battery> {
+        std::iter::once(&mut self.battery).chain(&mut self.linked)
+    }
+}
+
+/// The battery's readings at one point in the scenario
+struct Snapshot {
+    current: f32,
+    charge: f32,
+    cell_volts: Vec<f32>,
+    temp: f32,
+}
+
+/// Answers Modbus requests with readings that follow a scenario from the moment it is created
+///
+/// Each battery in the scenario answers on its own address.
+pub struct Simulator {
+    scenario: Scenario,
+    started: Instant,
+}
+
+impl Simulator {
+    pub fn new(scenario: Scenario) -> Self {
+        println!("Simulating scenario: {}", scenario.name);
+        Simulator {
+            scenario,
+            started: Instant::now(),
+        }
+    }
+
+    /// The response frame to the request `frame`
+    ///
+    /// Like a real device, nothing is returned for a corrupt request or an address no battery has.
+    pub fn respond(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 8 {
+            return None;
+        }
+        let address = frame[0];
+        let battery = self
+            .scenario
+            .batteries()
+            .find(|battery| battery.address() == address)?;
+        let crc = u16::from_le_bytes([frame[6], frame[7]]);
+        if crc16(&frame[..6]) != crc {
+            return None;
+        }
+
+        let function = frame[1];
+        let start = u16::from_be_bytes([frame[2], frame[3]]);
+        let count = u16::from_be_bytes([frame[4], frame[5]]);
+        let response = if function != READ_HOLDING_REGISTERS {
+            exception(address, function, ILLEGAL_FUNCTION)
+        } else {
+            match self.read(battery, start, count) {
+                Some(registers) => {
+                    let mut response = vec![address, function, (registers.len() * 2) as u8];
+                    for register in registers {
+                        response.extend_from_slice(&register.to_be_bytes());
+                    }
+                    response
+                }
+                None => exception(address, function, ILLEGAL_DATA_ADDRESS),
+            }
+        };
+        Some(with_crc(response))
+    }
+
+    /// Reads `count` registers from `start`, which must lie within one register block
+    fn read(&self, battery: &Battery, start: u16, count: u16) -> Option<Vec<u16>> {
+        let snapshot = battery.snapshot(self.started.elapsed().as_secs_f32());
+        let blocks = [
+            (LEVELS_REGISTER, LEVELS_COUNT),
+            (HEALTH_REGISTER, HEALTH_COUNT),
+            (CELL_VOLTS_REGISTER, CELL_VOLTS_COUNT),
+            (TEMPS_REGISTER, TEMPS_COUNT),
+            (STATUS_REGISTER, STATUS_COUNT),
+            (DEVICE_INFO_REGISTER, DEVICE_INFO_COUNT),
+        ];
+        let (block_start, block_count) =
+            blocks.into_iter().find(|(block_start, block_count)| {
+                start >= *block_start
+                    && u32::from(start) + u32::from(count)
+                        <= u32::from(*block_start) + u32::from(*block_count)
+            })?;
+
+        let mut registers = match block_start {
+            LEVELS_REGISTER => battery.levels(&snapshot),
+            HEALTH_REGISTER => battery.health(),
+            CELL_VOLTS_REGISTER => battery.cell_volts(&snapshot),
+            TEMPS_REGISTER => battery.temps(&snapshot),
+            STATUS_REGISTER => battery.status(&snapshot),
+            _ => battery.device_info(),
+        };
+        registers.resize(usize::from(block_count), 0);
+        let offset = usize::from(start - block_start);
+        Some(registers[offset..offset + usize::from(count)].to_vec())
+    }
+}
+
+impl Battery {
+    /// The address the battery answers on, once the scenario is parsed
+    pub fn address(&self) -> u8 {
+        self.address.unwrap_or(DEFAULT_ADDRESS)
+    }
+
+    /// Plays the battery's phases up to `elapsed` seconds in
+    fn snapshot(&self, elapsed: f32) -> Snapshot {
+        let mut remaining = elapsed;
+        let mut current = 0.0;
+        let mut charge = self.charge;
+        let mut temp = self.temp;
+        let mut drift = vec![0.0; usize::from(self.cells)];
+
+        for phase in &self.phases {
+            if remaining <= 0.0 {
+                break;
+            }
+            let seconds = remaining.min(phase.duration);
+            remaining -= phase.duration;
+            current = if remaining < 0.0 { phase.current } else { 0.0 };
+
+            charge = (charge + phase.current * seconds / 3600.0).clamp(0.0, self.capacity);
+            temp += phase.temp_rate * seconds / 60.0;
+            for (cell, rate) in drift.iter_mut().zip(&phase.cell_drift) {
+                *cell += rate * seconds / 60.0;
+            }
+        }
+
+        // A LiFePO4 cell sits between roughly 3.0V empty and 3.4V full, and rises with the
+        // charge current across its internal resistance
+        let state_of_charge = charge / self.capacity;
+        let cell_volt = 3.0 + 0.4 * state_of_charge + current * 0.002;
+        let cell_volts = drift.iter().map(|drift| cell_volt + drift).collect();
+
+        Snapshot {
+            current,
+            charge,
+            cell_volts,
+            temp,
+        }
+    }
+
+    fn levels(&self, snapshot: &Snapshot) -> Vec<u16> {
+        let volt: f32 = snapshot.cell_volts.iter().sum();
+        let charge = (snapshot.charge * 1000.0) as u32;
+        let capacity = (self.capacity * 1000.0) as u32;
+        vec![
+            (snapshot.current * 100.0) as i16 as u16,
+            (volt * 10.0) as u16,
+            (charge >> 16) as u16,
+            charge as u16,
+            (capacity >> 16) as u16,
+            capacity as u16,
+        ]
+    }
+
+    fn health(&self) -> Vec<u16> {
+        let capacity = (self.capacity * 1000.0) as u32;
+        let cells = f32::from(self.cells);
+        vec![
+            (capacity >> 16) as u16,
+            capacity as u16,
+            self.cycle_count,
+            (3.6 * cells * 10.0) as u16,
+            (2.5 * cells * 10.0) as u16,
+            (self.capacity / 2.0 * 100.0) as u16,
+            (-self.capacity * 100.0) as i16 as u16,
+        ]
+    }
+
+    fn cell_volts(&self, snapshot: &Snapshot) -> Vec<u16> {
+        let mut registers = vec![self.cells];
+        registers.extend(
+            snapshot
+                .cell_volts
+                .iter()
+                .map(|volt| (volt * 10.0).round() as u16),
+        );
+        registers
+    }
+
+    /// One sensor per cell, up to four, a little apart from each other
+    fn temps(&self, snapshot: &Snapshot) -> Vec<u16> {
+        let sensors = self.cells.min(4);
+        let mut registers = vec![sensors];
+        registers.extend(
+            (0..sensors)
+                .map(|sensor| ((snapshot.temp + f32::from(sensor) * 0.2) * 10.0) as i16 as u16),
+        );
+        registers
+    }
+
+    fn status(&self, snapshot: &Snapshot) -> Vec<u16> {
+        let (low, high) = CELL_VOLT_LIMITS;
+        let mut cell_volt_alarms: u32 = 0;
+        let mut cell_temp_alarms: u32 = 0;
+        for (cell, volt) in snapshot.cell_volts.iter().enumerate().take(16) {
+            if *volt < low {
+                cell_volt_alarms |= 0b01 << (cell * 2);
+            } else if *volt > high {
+                cell_volt_alarms |= 0b10 << (cell * 2);
+            }
+            if snapshot.temp > OVER_TEMP {
+                cell_temp_alarms |= 0b10 << (cell * 2);
+            }
+        }
+
+        let charging = snapshot.current > 0.0;
+        let discharging = snapshot.current < 0.0;
+        let over_temp = snapshot.temp > OVER_TEMP;
+        let high_temp = snapshot.temp > HIGH_TEMP;
+
+        // Bits as laid out in STATUS_1_FLAGS, STATUS_2_FLAGS and CHARGE_DISCHARGE_FLAGS
+        let mut status_1: u16 = 0;
+        if over_temp {
+            status_1 |= if discharging { 1 << 3 } else { 1 << 1 };
+        } else {
+            status_1 |= (1 << 13) | (1 << 14);
+        }
+        if cell_volt_alarms & 0x5555_5555 != 0 {
+            status_1 |= 1 << 8;
+        }
+        if cell_volt_alarms & 0xaaaa_aaaa != 0 {
+            status_1 |= 1 << 7;
+        }
+
+        let mut status_2: u16 = 0;
+        if high_temp {
+            status_2 |= if discharging { 1 << 7 } else { 1 << 5 };
+        }
+        if charging {
+            status_2 |= 1 << 14;
+        }
+        if discharging {
+            status_2 |= 1 << 15;
+        }
+
+        let charge_discharge: u16 = if over_temp { 0 } else { (1 << 6) | (1 << 7) };
+
+        vec![
+            (cell_volt_alarms >> 16) as u16,
+            cell_volt_alarms as u16,
+            (cell_temp_alarms >> 16) as u16,
+            cell_temp_alarms as u16,
+            0,
+            0,
+            status_1,
+            status_2,
+            0,
+            charge_discharge,
+        ]
+    }
+
+    fn device_info(&self) -> Vec<u16> {
+        let mut bytes = [0u8; DEVICE_INFO_COUNT as usize * 2];
+        // A serial number of its own, so linked batteries can be told apart
+        let serial = format!("SIM{:013x}", self.address());
+        let fields: [(usize, &str); 5] = [
+            (0, &serial),
+            (18, "0101"),
+            (24, "RBT100LFP12S-SIM"),
+            (40, "0101"),
+            (44, "Reno Simulator"),
+        ];
+        for (offset, value) in fields {
+            bytes[offset..offset + value.len()].copy_from_slice(value.as_bytes());
+        }
+        bytes
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect()
+    }
+}
+
+fn exception(address: u8, function: u8, code: u8) -> Vec<u8> {
+    vec![address, function | EXCEPTION_FLAG, code]
+}
+
+fn with_crc(mut frame: Vec<u8>) -> Vec<u8> {
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
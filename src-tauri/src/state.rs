@@ -2,11 +2,15 @@ use std::sync::Mutex;
 
 use btleplug::platform::{Adapter, Peripheral};
 
+use crate::simulator::Simulator;
+
 pub struct AppState {
     pub adapters: Mutex<Vec<Adapter>>,
     pub chosen_adapter: Mutex<Option<Adapter>>,
     pub peripherals: Mutex<Vec<Peripheral>>,
     pub chosen_peripheral: Mutex<Option<Peripheral>>,
+    /// Set with `--simulate`, and answers in place of a peripheral
+    pub simulator: Mutex<Option<Simulator>>,
 }
 
 impl Default for AppState {
@@ -16,6 +20,7 @@ impl Default for AppState {
             chosen_adapter: Mutex::new(None),
             peripherals: Mutex::new(Vec::new()),
             chosen_peripheral: Mutex::new(None),
+            simulator: Mutex::new(None),
         }
     }
 }
@@ -1,14 +1,19 @@
 import { invoke } from "@tauri-apps/api/tauri";
 import { UnlistenFn, emit, listen } from "@tauri-apps/api/event";
 import { useState, useRef, useEffect, useContext } from "react";
-import { DataDispatchContext } from "./state";
+import { BtModuleInfo, DataContext, DataDispatchContext } from "./state";
 
 export function PeripheralControls() {
   const [isSearching, setIsSearching] = useState(false);
   const [isRecording, setIsRecording] = useState(false);
   const [status, setStatus] = useState("Not Connected");
   const [peripheralId, setPeripheralId] = useState("");
+  const [loadOn, setLoadOn] = useState<boolean | null>(null);
+  const [moduleInfo, setModuleInfo] = useState<BtModuleInfo | null>(null);
   const dispatch = useContext(DataDispatchContext);
+  const data = useContext(DataContext);
+  const deviceInfo = [...data].reverse().find(({ data }) => data.DeviceInfo)
+    ?.data.DeviceInfo;
 
   const selectEl = useRef<HTMLSelectElement | null>(null);
 
@@ -76,7 +81,8 @@ export function PeripheralControls() {
         onClick={() => {
           setStatus("Connecting to Peripheral");
           invoke("connect_to_peripheral", { id: peripheralId })
-            .then(() => {
+            .then((info) => {
+              setModuleInfo(info as BtModuleInfo);
               setStatus("Connected to Peripheral");
             })
             .catch((e) => {
@@ -103,6 +109,28 @@ export function PeripheralControls() {
       >
         Get Latest
       </button>
+      <button
+        onClick={() => {
+          setStatus("Switching Load Output");
+          const command =
+            loadOn === null
+              ? invoke("get_load_output")
+              : invoke("set_load_output", { on: !loadOn });
+          command
+            .then((on) => {
+              setLoadOn(on as boolean);
+              setStatus(`Load Output ${on ? "On" : "Off"}`);
+            })
+            .catch((e) => {
+              console.error(e);
+              setStatus("Error Switching Load Output");
+            });
+        }}
+      >
+        {loadOn === null
+          ? "Get Load Output"
+          : `Turn Load ${loadOn ? "Off" : "On"}`}
+      </button>
       <button
         onClick={() => {
           console.log(1);
@@ -143,6 +171,20 @@ export function PeripheralControls() {
       >
         {isRecording ? "Stop Recording" : "Record"} Data Stream
       </button>
+      {moduleInfo && (
+        <div className="bt-module-info">
+          BT module: {moduleInfo.manufacturer_name ?? "Unknown"}{" "}
+          {moduleInfo.model_number ?? ""} (firmware{" "}
+          {moduleInfo.firmware_revision ?? "unknown"}, software{" "}
+          {moduleInfo.software_revision ?? "unknown"})
+        </div>
+      )}
+      {deviceInfo && (
+        <div className="device-info">
+          {deviceInfo.manufacturer} {deviceInfo.model} (S/N{" "}
+          {deviceInfo.serial_number}, firmware {deviceInfo.firmware_version})
+        </div>
+      )}
       {/* Show errors or loading */}
       <div className="status">{status}</div>
     </fieldset>
@@ -3,12 +3,25 @@ import { Dispatch, createContext } from "react";
 type Data = {
   data: DataType;
   timestamp: number;
+  address?: number;
+  device?: string;
 };
 
 type DataType = {
   Levels?: Levels;
   CellVolts?: CellVolts;
   Temps?: Temps;
+  Health?: Health;
+  Status?: Status;
+  DeviceInfo?: DeviceInfo;
+  Charging?: Charging;
+  DailyStats?: DailyStats;
+  ChargeState?: ChargeState;
+  DcCharging?: DcCharging;
+  ChargeStage?: ChargeStage;
+  BtModule?: BtModuleInfo;
+  Disconnected?: Disconnected;
+  Reconnected?: Reconnected;
 };
 
 type Levels = {
@@ -26,6 +39,103 @@ type Temps = {
   temps: number[];
 };
 
+type Health = {
+  cycle_count: number;
+  design_capacity: number;
+  charge_volt_limit: number;
+  discharge_volt_limit: number;
+  charge_current_limit: number;
+  discharge_current_limit: number;
+};
+
+type Status = {
+  flags: string[];
+  cell_volt_alarms: CellAlarm[];
+  cell_temp_alarms: CellAlarm[];
+};
+
+type CellAlarm = {
+  cell: number;
+  alarm: "BelowLowerLimit" | "AboveUpperLimit" | "OtherFault";
+};
+
+type DeviceInfo = {
+  model: string;
+  serial_number: string;
+  firmware_version: string;
+  software_version: string;
+  manufacturer: string;
+};
+
+type Charging = {
+  battery_soc: number;
+  battery_volt: number;
+  charging_current: number;
+  controller_temp: number;
+  battery_temp: number;
+  load_volt: number;
+  load_current: number;
+  load_power: number;
+  pv_volt: number;
+  pv_current: number;
+  pv_power: number;
+};
+
+type DailyStats = {
+  min_battery_volt: number;
+  max_battery_volt: number;
+  max_charging_current: number;
+  max_discharging_current: number;
+  max_charging_power: number;
+  max_discharging_power: number;
+  charging_amp_hours: number;
+  discharging_amp_hours: number;
+  generation: number;
+  consumption: number;
+};
+
+type ChargeState = {
+  load_on: boolean;
+  charging_state: string | { Unknown: number };
+};
+
+type DcCharging = {
+  battery_soc: number;
+  battery_volt: number;
+  charging_current: number;
+  controller_temp: number;
+  battery_temp: number;
+  alternator_volt: number;
+  alternator_current: number;
+  alternator_power: number;
+  pv_volt: number;
+  pv_current: number;
+  pv_power: number;
+};
+
+type ChargeStage = {
+  charging_state: string | { Unknown: number };
+};
+
+export type BtModuleInfo = {
+  system_id: string | null;
+  model_number: string | null;
+  serial_number: string | null;
+  firmware_revision: string | null;
+  hardware_revision: string | null;
+  software_revision: string | null;
+  manufacturer_name: string | null;
+  pnp_id: string | null;
+};
+
+type Disconnected = {
+  reason: string;
+};
+
+type Reconnected = {
+  attempts: number;
+};
+
 export const DataContext = createContext<Data[]>([]);
 export const DataDispatchContext = createContext<Dispatch<any>>(() => {});
 